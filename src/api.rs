@@ -6,25 +6,65 @@
 //! - Middleware (e.g., nosniff, http caching)
 //! - Endpoints (with routing)
 
-use std::{fmt::Debug, path::PathBuf, sync::Arc};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::{Duration, Instant},
+};
 
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
 use async_trait::async_trait;
 use axum::{
-    body::Body,
-    extract::State,
+    body::{Body, HttpBody, StreamBody},
+    extract::ws::{Message, WebSocketUpgrade},
+    extract::{
+        path::ErrorKind as PathErrorKind, rejection::PathRejection,
+        ConnectInfo, Query, State,
+    },
     http::{self, header, HeaderValue, StatusCode},
     middleware::{from_fn, from_fn_with_state, Next},
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, get_service},
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
 use bytes::BytesMut;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use notify::{RecursiveMode, Watcher};
+use percent_encoding::{
+    percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 use tokio_stream::StreamExt;
-use tower_http::services::ServeDir;
+use tokio_util::io::ReaderStream;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
+};
+use tracing::Instrument;
 
-use crate::{fs::*, prim::*, thumb::*};
+use crate::{archive::*, fs::*, prim::*, sign::*, thumb::*};
 
 /// API Error
 ///
@@ -87,12 +127,18 @@ use crate::{fs::*, prim::*, thumb::*};
 ///
 /// ## Using [`ApiError`] and [`ApiResult`] in [`axum`] endpoints
 ///
-/// Just throw it. It will be converted into plain text (yes, just
-/// plain text, not JSON) with the status code you provided.
+/// Just throw it. It will be converted into a JSON object with the
+/// status code you provided, like so:
+///
+/// ```json
+/// {"error": {"status": 404, "kind": "not_found", "message": "Not Found"}}
+/// ```
 ///
-/// The end user will see the canonical error message if one is
-/// associated with the status code. If the canonical error message
-/// doesn't exist, the user will be greated with an empty response.
+/// The `kind` is an [`ApiErrorKind`], a stable, machine-readable
+/// value for clients to switch on. The `message` is the canonical
+/// reason of the status code (or an empty string if there is none).
+/// The underlying [`type@Error`] never reaches the end user; it's
+/// logged instead.
 ///
 /// The HTTP status code will be set to the one you provided.
 ///
@@ -100,6 +146,45 @@ use crate::{fs::*, prim::*, thumb::*};
 #[derive(Debug, Error)]
 pub struct ApiError(http::StatusCode, #[source] Error);
 
+/// Machine-readable kind of an [`ApiError`], decided by its status
+/// code
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorKind {
+    /// 400
+    BadRequest,
+    /// 401 or 403
+    Forbidden,
+    /// 404
+    NotFound,
+    /// 409 (such as a move onto an existing file)
+    Conflict,
+    /// 415 (such as a file that can't be decoded as an image)
+    Unsupported,
+    /// 508 (a loop of links)
+    LinkLoop,
+    /// Any other 5xx
+    Internal,
+    /// Anything else
+    Other,
+}
+
+impl From<StatusCode> for ApiErrorKind {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::Unsupported,
+            StatusCode::LOOP_DETECTED => Self::LinkLoop,
+            s if s.is_server_error() => Self::Internal,
+            _ => Self::Other,
+        }
+    }
+}
+
 impl std::fmt::Display for ApiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "ApiError {}: {:?}", self.0, self.1)
@@ -127,7 +212,25 @@ impl From<(StatusCode, Error)> for ApiError {
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
         let status = self.0;
-        (status, status.canonical_reason().unwrap_or_default()).into_response()
+        if status.is_server_error() {
+            tracing::warn!("{self}");
+        } else {
+            tracing::debug!("{self}");
+        }
+        let body = json!({
+            "error": {
+                "status": status.as_u16(),
+                "kind": ApiErrorKind::from(status),
+                "message": status.canonical_reason().unwrap_or_default(),
+            }
+        })
+        .to_string();
+        (
+            status,
+            [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+            body,
+        )
+            .into_response()
     }
 }
 
@@ -138,13 +241,15 @@ type ApiResult<T> = std::result::Result<T, ApiError>;
 ///
 /// If so, then get the metadata of the object after following all links.
 async fn follow_get_md(
+    backend: &dyn OpenFile,
     chroot: &RealPath,
     vpath: &VirtualPath,
 ) -> ApiResult<FileMetadata> {
     // Canonicalize the path
-    let cpath = canonicalize(&chroot, vpath)
+    let cpath = backend
+        .canonicalize(chroot, vpath)
         .await
-        .map_err(ApiError::with_status(404))?;
+        .map_err(canonicalize_error)?;
 
     // Strip the prefix to get the virtual path back.
     // It also checks whether the path is inside the chroot.
@@ -154,13 +259,48 @@ async fn follow_get_md(
         .map_err(ApiError::with_status(404))?;
 
     // Check the metadata
-    let meta = read_metadata(&chroot, &vpath)
+    let meta = backend
+        .read_metadata(chroot, vpath)
         .await
         .map_err(ApiError::with_status(404))?;
 
     Ok(meta)
 }
 
+/// Turn a failure to canonicalize into 508 Loop Detected, if it's due
+/// to a loop of links, or 404 Not Found otherwise
+pub(crate) fn canonicalize_error(e: Error) -> ApiError {
+    if is_link_loop(&e) {
+        ApiError::with_status(StatusCode::LOOP_DETECTED)(e)
+    } else {
+        ApiError::with_status(404)(e)
+    }
+}
+
+/// Why a link is broken, if it is, that is, if it can't be resolved
+/// (as opposed to leading somewhere it shouldn't): `"loop"` if it
+/// leads around in a loop of links, and `"missing"` otherwise.
+async fn broken_link(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+) -> Option<&'static str> {
+    match backend.canonicalize(chroot, vpath).await {
+        Ok(_) => None,
+        Err(e) if is_link_loop(&e) => Some("loop"),
+        Err(_) => Some("missing"),
+    }
+}
+
+/// Whether a link is broken (see [`broken_link`])
+async fn is_broken_link(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+) -> bool {
+    broken_link(backend, chroot, vpath).await.is_some()
+}
+
 /// The Chroot type
 ///
 /// This is the directory to serve files from, shared across all
@@ -201,6 +341,384 @@ async fn mw_set_chroot<B>(
     next.run(req).await
 }
 
+/// The storage backend (as an HTTP extension)
+///
+/// Like the [`Chroot`], it's set once at startup.
+#[derive(Debug, Clone)]
+struct Backend(Arc<dyn OpenFile>);
+
+/// Allow Backend to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for Backend {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        let backend = parts
+            .extensions
+            .get::<Backend>()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("backend not set"))
+            })
+            .map(|backend| backend.clone())?;
+        Ok(backend)
+    }
+}
+
+/// Set the Backend in the request
+#[instrument(skip(req, next))]
+async fn mw_set_backend<B>(
+    State(backend): State<Arc<dyn OpenFile>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(Backend(backend));
+    next.run(req).await
+}
+
+/// Rules for listing directories, set once at startup
+#[derive(Debug, Clone)]
+pub struct ListPolicy {
+    /// List at most this many entries (directories and files
+    /// together). Beyond that, the listing is cut short and marked
+    /// as `truncated`.
+    pub max_entries: usize,
+    /// Follow links, reporting them as whatever they point to. If
+    /// not, report links as such (`ln`, among the files) without
+    /// looking at their targets, which also keeps broken links.
+    pub follow_symlinks: bool,
+    /// List entries whose names aren't UTF-8, under a lossy name.
+    /// (From version "041" of the list API, the raw name is given
+    /// too, percent-encoded, as `"raw"`.) If not, they are skipped.
+    pub lossy_names: bool,
+    /// Follow at most this many links at once while listing. On
+    /// storage with high latency, more is faster.
+    pub concurrency: usize,
+}
+
+impl Default for ListPolicy {
+    fn default() -> Self {
+        Self {
+            max_entries: 3000,
+            follow_symlinks: true,
+            lossy_names: false,
+            concurrency: 16,
+        }
+    }
+}
+
+/// The [`ListPolicy`] (as an HTTP extension)
+#[derive(Debug, Clone)]
+struct Policy(Arc<ListPolicy>);
+
+/// Allow Policy to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for Policy {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        let policy = parts
+            .extensions
+            .get::<Policy>()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("policy not set"))
+            })
+            .map(|policy| policy.clone())?;
+        Ok(policy)
+    }
+}
+
+/// Set the Policy in the request
+#[instrument(skip(req, next))]
+async fn mw_set_policy<B>(
+    State(policy): State<Arc<ListPolicy>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(Policy(policy));
+    next.run(req).await
+}
+
+/// Rules for which objects are served at all, set once at startup,
+/// and shared by all services
+///
+/// Whatever is hidden is left out of listings, searches, and
+/// archives, and asking for it (or for anything inside a hidden
+/// directory) gets 404 Not Found. Besides these rules, each directory
+/// may hide more with an ignore file (see [`IGNORE_FILE`]).
+#[derive(Debug, Clone)]
+pub struct VisibilityPolicy {
+    /// Serve hidden objects, those whose names start with a `.` (such
+    /// as `.git`, `.env`, or `.htpasswd`).
+    pub show_hidden: bool,
+    /// Hide objects matching any of these globs (see
+    /// [`VisibilityPolicy::ignoring`]). Each is matched against the
+    /// virtual path (relative to the chroot, without a leading `/`)
+    /// and against the name alone, so `*.tmp` and `node_modules`
+    /// hide such objects at any depth, while `build/*.log` only
+    /// hides logs under the top-level `build`.
+    pub ignore: GlobSet,
+}
+
+impl Default for VisibilityPolicy {
+    fn default() -> Self {
+        Self {
+            show_hidden: false,
+            ignore: GlobSet::empty(),
+        }
+    }
+}
+
+impl VisibilityPolicy {
+    /// Replace the ignore list with these globs (such as `*.tmp`,
+    /// `Thumbs.db`, or `__pycache__`)
+    pub fn ignoring<S: AsRef<str>>(
+        mut self,
+        globs: impl IntoIterator<Item = S>,
+    ) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            let glob = glob.as_ref();
+            builder.add(Glob::new(glob).with_context(|| format!("{glob:?}"))?);
+        }
+        self.ignore = builder.build().context("compile ignore globs")?;
+        Ok(self)
+    }
+
+    /// Whether the object at the (virtual) path is hidden, that is,
+    /// whether it or any directory above it is hidden by name or
+    /// ignored
+    fn hides(&self, vpath: &VirtualPath) -> bool {
+        let mut prefix = PathBuf::new();
+        vpath.components().any(|c| {
+            let std::path::Component::Normal(name) = c else {
+                return false;
+            };
+            prefix.push(name);
+            (!self.show_hidden && name.to_string_lossy().starts_with('.'))
+                || self.ignore.is_match(name)
+                || self.ignore.is_match(&prefix)
+        })
+    }
+}
+
+/// Name of the per-directory ignore file
+///
+/// Its patterns (in the syntax of `.gitignore`) hide the objects they
+/// match in the same directory and below it. Deeper files take
+/// precedence, so they can unhide (`!pattern`) what is hidden above.
+const IGNORE_FILE: &str = ".gagagaignore";
+
+/// The rules of an ignore file, and the metadata of the file that they
+/// were read from
+type IgnoreRules = (FileMetadata, Arc<Gitignore>);
+
+/// Ignore files (see [`IGNORE_FILE`]), parsed and remembered by the
+/// virtual path of their directory along with the size and last
+/// modified time that they were read at
+#[derive(Debug, Clone, Default)]
+struct IgnoreFiles(Arc<Mutex<HashMap<PathBuf, IgnoreRules>>>);
+
+impl IgnoreFiles {
+    /// Forget everything once this many directories are remembered
+    const CAPACITY: usize = 10_000;
+
+    /// Read at most this many bytes of an ignore file
+    const MAX_LEN: u64 = 64 * 1024;
+
+    /// Get the rules of the ignore file in the directory, if there is
+    /// one, reading it only if it's not remembered or it has changed
+    /// since (judging by the size and last modified time).
+    async fn rules(
+        &self,
+        backend: &dyn OpenFile,
+        chroot: &RealPath,
+        dir: &VirtualPath,
+    ) -> Option<Arc<Gitignore>> {
+        let path = dir.join(IGNORE_FILE);
+        let md = backend.read_metadata(chroot, &path).await.ok()?;
+        if md.file_type != FileType::RegularFile {
+            return None;
+        }
+        let known = self.0.lock().unwrap().get(dir).cloned();
+        if let Some((known, rules)) = known {
+            if known.size == md.size && known.last_modified == md.last_modified
+            {
+                return Some(rules);
+            }
+        }
+
+        let mut text = String::new();
+        let read = match backend.open_file(chroot, &path).await {
+            Ok(file) => file
+                .take(Self::MAX_LEN)
+                .read_to_string(&mut text)
+                .await
+                .map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = read {
+            tracing::warn!("read {path:?}: {e:?}");
+            return None;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        for line in text.lines() {
+            if let Err(e) = builder.add_line(None, line) {
+                tracing::warn!("{path:?}: skip {line:?}: {e}");
+            }
+        }
+        let rules = match builder.build() {
+            Ok(rules) => Arc::new(rules),
+            Err(e) => {
+                tracing::warn!("{path:?}: {e}");
+                return None;
+            }
+        };
+
+        let mut files = self.0.lock().unwrap();
+        if files.len() >= Self::CAPACITY {
+            files.clear();
+        }
+        files.insert(dir.to_owned(), (md, rules.clone()));
+        Some(rules)
+    }
+}
+
+/// What is hidden inside one directory: whatever the
+/// [`VisibilityPolicy`] hides, and whatever the ignore files of the
+/// directory and of those above it hide
+#[derive(Debug)]
+struct Hider {
+    policy: Arc<VisibilityPolicy>,
+    /// Innermost first
+    ignores: Vec<Arc<Gitignore>>,
+}
+
+impl Hider {
+    /// Whether the object at the (virtual) path, which must be inside
+    /// the directory, is hidden
+    fn hides(&self, vpath: &VirtualPath, is_dir: bool) -> bool {
+        if self.policy.hides(vpath) {
+            return true;
+        }
+        for rules in &self.ignores {
+            match rules.matched_path_or_any_parents(vpath, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// The [`VisibilityPolicy`], along with the ignore files read so far
+/// (as an HTTP extension)
+#[derive(Debug, Clone)]
+pub struct Visibility {
+    policy: Arc<VisibilityPolicy>,
+    ignore_files: IgnoreFiles,
+}
+
+impl Visibility {
+    pub fn new(policy: VisibilityPolicy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            ignore_files: IgnoreFiles::default(),
+        }
+    }
+
+    /// Find out what is hidden inside the directory
+    async fn in_dir(
+        &self,
+        backend: &dyn OpenFile,
+        chroot: &RealPath,
+        dir: &VirtualPath,
+    ) -> Hider {
+        let mut ignores = vec![];
+        for dir in dir.ancestors() {
+            if let Some(rules) =
+                self.ignore_files.rules(backend, chroot, dir).await
+            {
+                ignores.push(rules);
+            }
+        }
+        Hider {
+            policy: self.policy.clone(),
+            ignores,
+        }
+    }
+
+    /// Whether the object at the (virtual) path is hidden. The chroot
+    /// itself never is.
+    async fn hides(
+        &self,
+        backend: &dyn OpenFile,
+        chroot: &RealPath,
+        vpath: &VirtualPath,
+        is_dir: bool,
+    ) -> bool {
+        let Some(dir) = vpath.parent() else {
+            return false;
+        };
+        self.in_dir(backend, chroot, dir).await.hides(vpath, is_dir)
+    }
+}
+
+/// Allow Visibility to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for Visibility {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        let visibility = parts
+            .extensions
+            .get::<Visibility>()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("visibility not set"))
+            })
+            .map(|visibility| visibility.clone())?;
+        Ok(visibility)
+    }
+}
+
+/// Set the Visibility in the request
+///
+/// Services without it hide hidden objects, as if by
+/// [`VisibilityPolicy::default`].
+#[instrument(skip(req, next))]
+pub async fn mw_set_visibility<B>(
+    State(visibility): State<Visibility>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(visibility);
+    next.run(req).await
+}
+
+/// Use the visibility if set, and the default (hide hidden objects)
+/// otherwise.
+fn visibility_or_default(visibility: Option<Visibility>) -> Visibility {
+    visibility.unwrap_or_else(|| Visibility::new(VisibilityPolicy::default()))
+}
+
+/// Use the backend if set. Services that don't set one only ever
+/// serve the local file system.
+fn backend_or_local(backend: Option<Backend>) -> Arc<dyn OpenFile> {
+    backend.map_or_else(|| Arc::new(LocalFile) as _, |b| b.0)
+}
+
 /// Allow VPath to be extracted from the request
 #[async_trait]
 impl axum::extract::FromRequestParts<()> for VPath {
@@ -224,21 +742,60 @@ impl axum::extract::FromRequestParts<()> for VPath {
 #[derive(Debug, Clone)]
 struct VPath(Arc<PathBuf>);
 
-/// Only continue if the path is valid.
+/// Check a virtual path to create an object at (a file if not
+/// `is_dir`), which need not exist, but whose directory must, and
+/// return it without the leading `/`
 ///
-/// Set VPath in the request extensions.
-#[instrument(skip(req, next), err)]
-async fn mw_guard_virt_path(
-    Chroot(chroot): Chroot,
-    vpath: Option<axum::extract::Path<PathBuf>>,
-    mut req: http::Request<Body>,
-    next: Next<Body>,
-) -> ApiResult<impl IntoResponse> {
-    // Extract PathBuf
-    let vpath = vpath.map(|vpath| vpath.0).unwrap_or_default();
+/// The directory is checked as [`check_virt_path`] checks any path,
+/// and the object must not be hidden (403 Forbidden) once it exists.
+async fn check_new_virt_path(
+    backend: &dyn OpenFile,
+    visibility: &Visibility,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    is_dir: bool,
+) -> ApiResult<PathBuf> {
+    if bad_path1(vpath) {
+        return Err(ApiError::with_status(400)(anyhow!(
+            "bad new vpath: {vpath:?}"
+        )));
+    }
+    let vpath = vpath.strip_prefix("/").unwrap_or(vpath);
+    let (Some(parent), Some(name)) = (vpath.parent(), vpath.file_name()) else {
+        return Err(ApiError::with_status(400)(anyhow!(
+            "bad new vpath: {vpath:?}"
+        )));
+    };
+    let parent = check_virt_path(backend, visibility, chroot, parent).await?;
+    // (The parent is canonical, and may be the chroot itself.)
+    let parent_is_dir = backend
+        .read_link_metadata(chroot, &parent)
+        .await
+        .is_ok_and(|md| md.file_type == FileType::Directory);
+    if !parent_is_dir {
+        return Err(ApiError::with_status(404)(anyhow!(
+            "no directory: {parent:?}"
+        )));
+    }
+    let vpath = parent.join(name);
+    if visibility.hides(backend, chroot, &vpath, is_dir).await {
+        return Err(ApiError::with_status(403)(anyhow!(
+            "hidden new vpath: {vpath:?}"
+        )));
+    }
+    Ok(vpath)
+}
 
+/// Check a virtual path (as decoded from a URL), as
+/// [`mw_guard_virt_path`] does, and return it without the leading `/`
+async fn check_virt_path(
+    backend: &dyn OpenFile,
+    visibility: &Visibility,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+) -> ApiResult<PathBuf> {
     // Quick check
-    if bad_path1(&vpath) {
+    if bad_path1(vpath) {
         return Err((
             StatusCode::BAD_REQUEST,
             anyhow!("chk 1/3 bad vpath (quick): {vpath:?}"),
@@ -247,17 +804,18 @@ async fn mw_guard_virt_path(
     }
 
     // Strip leading '/', which causes the `join` to silently fail.
-    let vpath = vpath.strip_prefix("/").unwrap_or(&vpath);
+    let vpath = vpath.strip_prefix("/").unwrap_or(vpath);
 
     // Construct the real path
     let real_path = chroot.join(vpath);
     tracing::trace!("real_path: {real_path:?}");
 
     // Inclusivity check (follow symlinks)
-    let real_path = canonicalize(&*chroot, &vpath)
+    let real_path = backend
+        .canonicalize(chroot, vpath)
         .await
-        .map_err(ApiError::with_status(404))?;
-    if !real_path.starts_with(&*chroot) {
+        .map_err(canonicalize_error)?;
+    if !real_path.starts_with(chroot) {
         return Err((
             StatusCode::BAD_REQUEST,
             anyhow!("chk 2/3 bad real path (incl): {real_path:?}"),
@@ -274,13 +832,117 @@ async fn mw_guard_virt_path(
             .into());
     }
 
+    // Hidden objects are not found, whether asked for directly or
+    // through a link
+    let is_dir = backend
+        .read_metadata(chroot, vpath)
+        .await
+        .is_ok_and(|md| md.file_type == FileType::Directory);
+    let mut hidden = visibility.hides(backend, chroot, vpath, is_dir).await;
+    if let Ok(rpath) = real_path.strip_prefix(chroot) {
+        hidden =
+            hidden || visibility.hides(backend, chroot, rpath, is_dir).await;
+    }
+    if hidden {
+        return Err(ApiError::with_status(404)(anyhow!(
+            "hidden vpath: {vpath:?}"
+        )));
+    }
+
+    Ok(vpath.to_owned())
+}
+
+/// Only continue if the path is valid, and not hidden (see
+/// [`VisibilityPolicy`]).
+///
+/// Set VPath in the request extensions.
+#[instrument(skip(req, next), err)]
+async fn mw_guard_virt_path(
+    backend: Option<Backend>,
+    visibility: Option<Visibility>,
+    Chroot(chroot): Chroot,
+    vpath: std::result::Result<axum::extract::Path<PathBuf>, PathRejection>,
+    mut req: http::Request<Body>,
+    next: Next<Body>,
+) -> ApiResult<impl IntoResponse> {
+    // Extract PathBuf. No path at all is the root, but one that fails
+    // to decode (such as one that isn't UTF-8) is an error.
+    let vpath = match vpath {
+        Ok(vpath) => vpath.0,
+        Err(PathRejection::FailedToDeserializePathParams(e))
+            if matches!(
+                e.kind(),
+                PathErrorKind::WrongNumberOfParameters { got: 0, .. }
+            ) =>
+        {
+            PathBuf::new()
+        }
+        Err(PathRejection::MissingPathParams(_)) => PathBuf::new(),
+        Err(e) => {
+            return Err(ApiError::with_status(400)(anyhow!(
+                "chk 0/3 undecodable vpath: {e}"
+            )))
+        }
+    };
+
+    // Check
+    let backend = backend_or_local(backend);
+    let visibility = visibility_or_default(visibility);
+    let vpath =
+        check_virt_path(&*backend, &visibility, &chroot, &vpath).await?;
+
     // Set
-    req.extensions_mut()
-        .insert(VPath(Arc::new(vpath.to_owned())));
+    req.extensions_mut().insert(VPath(Arc::new(vpath)));
 
     Ok(next.run(req).await)
 }
 
+/// Longest URI (path and query) accepted, in bytes. Paths are at most
+/// 2 KiB (see [`bad_path1`]), so this leaves room for the query.
+const MAX_URI_LEN: usize = 4096;
+
+/// Read and send files this many bytes at a time, unless set otherwise
+/// (see [`DownloadPolicy::chunk_size`] and [`ThumbPolicy::chunk_size`])
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Turn away requests whose URI (path and query) is longer than
+/// (MAX) bytes with 414 URI Too Long, before anything else is done
+/// with the path.
+#[instrument(skip(req, next))]
+async fn mw_limit_uri_len<const MAX: usize, B>(
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let len = req.uri().path_and_query().map_or(0, |pq| pq.as_str().len());
+    if len > MAX {
+        return ApiError::with_status(414)(anyhow!("uri too long ({len})"))
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// The methods allowed by the services that only read (GET and HEAD,
+/// which every `get` route also answers), for [`mw_options`]
+pub const ALLOW_GET: &str = "GET, HEAD, OPTIONS";
+
+/// Answer `OPTIONS` requests with 204 No Content and the methods
+/// that the service allows (in `Allow`), without going any further
+/// (so that, for example, no archive is made for a directory).
+///
+/// CORS preflights are answered by the CORS layer instead, if any
+/// (see [`CorsPolicy`]).
+#[instrument(skip(req, next))]
+pub async fn mw_options<B>(
+    State(allow): State<&'static str>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    if req.method() != http::Method::OPTIONS {
+        return next.run(req).await;
+    }
+    (StatusCode::NO_CONTENT, [(header::ALLOW, allow)]).into_response()
+}
+
 /// No sniff
 ///
 /// Set the `X-Content-Type-Options` header to `nosniff`.
@@ -297,217 +959,4262 @@ async fn mw_nosniff<B: Debug>(
     res
 }
 
-/// Thumbnail API
-///
-/// Thumbnail a file with a maximum tolerance of reading (N) MB.
-#[instrument(err)]
-async fn api_thumb<const LIMITMB: usize>(
-    Chroot(chroot): Chroot,
-    VPath(vpath): VPath,
-) -> ApiResult<impl IntoResponse> {
-    // Open file, read file, check length
-    let real_path = chroot.join(&*vpath);
-    let mut file = tokio::fs::File::open(&real_path)
-        .await
-        .context("open file")
-        .map_err(ApiError::with_status(404))?;
-    // +1 is to detect over-reading.
-    let cap = LIMITMB * 1024 * 1024 + 1;
-    let mut buf = BytesMut::new();
-    loop {
-        let n = file
-            .read_buf(&mut buf)
-            .await
-            .context("read file")
-            .map_err(ApiError::with_status(404))?;
-        if n == 0 {
-            break;
+/// Security headers for browsers, set once at startup (see
+/// [`mw_security_headers`]). Each is left out if `None`.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    /// `Content-Security-Policy`
+    pub content_security_policy: Option<String>,
+    /// `X-Frame-Options`
+    pub frame_options: Option<String>,
+    /// `Referrer-Policy`
+    pub referrer_policy: Option<String>,
+    /// `Permissions-Policy`
+    pub permissions_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    /// For pages (such as the front-end's): no scripts, plugins, or
+    /// framing, but images from anywhere (the thumbnails are served
+    /// from another origin) and inline styles (the error pages have
+    /// them)
+    fn default() -> Self {
+        Self {
+            content_security_policy: Some(
+                "default-src 'self'; img-src * data:; \
+                style-src 'self' 'unsafe-inline'; script-src 'none'; \
+                object-src 'none'; base-uri 'none'; form-action 'self'; \
+                frame-ancestors 'none'"
+                    .to_string(),
+            ),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("no-referrer".to_string()),
+            permissions_policy: Some(
+                "camera=(), microphone=(), geolocation=(), payment=(), usb=()"
+                    .to_string(),
+            ),
         }
-        if buf.len() > cap {
-            return Err(ApiError::with_status(404)(anyhow!("file too large")));
+    }
+}
+
+impl SecurityHeaders {
+    /// For arbitrary files and API responses, which are never meant
+    /// to run: load nothing but the file's own images, media, and
+    /// inline styles, and sandbox the rest (so that HTML files can't
+    /// run scripts on the server's origin)
+    pub fn for_files() -> Self {
+        Self {
+            content_security_policy: Some(
+                "default-src 'none'; img-src 'self'; media-src 'self'; \
+                style-src 'unsafe-inline'; sandbox; frame-ancestors 'none'"
+                    .to_string(),
+            ),
+            ..Default::default()
         }
     }
+}
 
-    // Make thumbnail. ::<width, height, quality%>
-    let jpg = tokio::spawn(async move { ithumbjpg::<16, 16, 50>(&buf) })
-        .await
-        .context("spawn thumbnailing task")
-        .map_err(ApiError::with_status(500))?
-        .context("thumbnailing")
-        .map_err(ApiError::with_status(404))?;
+/// Set the [`SecurityHeaders`] on every response (composes with
+/// `X-Content-Type-Options`, which the services set themselves).
+/// Headers that the response already has are kept, and values that
+/// aren't valid header values are skipped.
+#[instrument(skip(req, next))]
+pub async fn mw_security_headers<B>(
+    State(headers): State<Arc<SecurityHeaders>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut res = next.run(req).await;
+    let all = [
+        (
+            header::CONTENT_SECURITY_POLICY,
+            &headers.content_security_policy,
+        ),
+        (header::X_FRAME_OPTIONS, &headers.frame_options),
+        (header::REFERRER_POLICY, &headers.referrer_policy),
+        (
+            http::HeaderName::from_static("permissions-policy"),
+            &headers.permissions_policy,
+        ),
+    ];
+    for (name, value) in all {
+        let Some(value) = value.as_deref() else {
+            continue;
+        };
+        let Ok(value) = HeaderValue::from_str(value) else {
+            continue;
+        };
+        res.headers_mut().entry(name).or_insert(value);
+    }
+    res
+}
 
-    // Response
-    Ok(([(header::CONTENT_TYPE, "image/jpeg")], jpg))
+/// Which other origins (web apps) may call an API from browsers,
+/// set once at startup. No origins (the default) means no CORS at all,
+/// so that only same-origin pages can read the responses.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// Allowed origins (such as `https://app.example.com`), or `*`
+    /// for any
+    pub allowed_origins: Vec<String>,
+    /// Allowed methods (GET and HEAD by default)
+    pub allowed_methods: Vec<http::Method>,
+    /// Allowed request headers (by default, those for authentication,
+    /// revalidation, and ranges)
+    pub allowed_headers: Vec<http::HeaderName>,
 }
 
-/// HTTP caching for files and directories in general by comparing
-/// If-Modified-Since (only). This requires the client to ask the
-/// server for revalidation each time the cache is used.
-#[instrument(skip(req, next), err)]
-async fn mw_cache_http_reval_lmo(
-    Chroot(chroot): Chroot,
-    VPath(vpath): VPath,
-    req: http::Request<Body>,
-    next: Next<Body>,
-) -> ApiResult<Response> {
-    // Read the metadata from the file system and its last modified -> lmo
-    let md = read_metadata(&*chroot, &*vpath).await;
-    let md = match md {
-        Ok(md) => md,
-        Err(e) => {
-            tracing::warn!("read_metadata: {e:?}");
-            return Ok(next.run(req).await);
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: vec![http::Method::GET, http::Method::HEAD],
+            allowed_headers: vec![
+                header::AUTHORIZATION,
+                header::IF_NONE_MATCH,
+                header::IF_MODIFIED_SINCE,
+                header::RANGE,
+            ],
         }
-    };
-    let lmo = md.last_modified;
-    if lmo.is_none() {
-        tracing::trace!("no last modified for virtual path {vpath:?}");
-        return Ok(next.run(req).await);
     }
-    let lmo = lmo.unwrap();
-    tracing::trace!("could read last modified from the file system");
-    // NOTE: Once I have the last modified date from the file system,
-    // I can send Cache-Control.
+}
 
-    // Get HTTP Last Modified date from the client
-    // (If-Modified-Since) -> hmo
-    let hmo = req.headers().get(header::IF_MODIFIED_SINCE);
-    if let Some(hmo) = hmo {
-        tracing::trace!("client sent if-modified-since");
-        let hmo = hmo
-            .to_str()
-            .context("convert if-modified-since to &str")
-            .map_err(ApiError::with_status(400))?;
-        let hmo = DateTime::from_http(hmo)
-            .context("convert &str if-modified-since to DateTime")
-            .map_err(ApiError::with_status(400))?;
-        // If lmo is earlier than hmo, or equal, then fresh.
-        if lmo.seccmp(&hmo).is_le() {
-            tracing::trace!("fresh");
-            return Ok(StatusCode::NOT_MODIFIED.into_response());
+impl CorsPolicy {
+    /// Make the CORS layer, if any origin is allowed. Preflight
+    /// (`OPTIONS`) requests are answered by the layer itself.
+    ///
+    /// Panics if an origin isn't a valid header value.
+    fn layer(&self) -> Option<CorsLayer> {
+        if self.allowed_origins.is_empty() {
+            return None;
         }
-        tracing::trace!("stale");
-    } else {
-        tracing::trace!("no if-modified-since header from client");
+        let origins = if self.allowed_origins.iter().any(|o| o == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.allowed_origins.iter().map(|origin| {
+                HeaderValue::from_str(origin)
+                    .expect("expect an origin to be a valid header value")
+            }))
+        };
+        Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods(self.allowed_methods.clone())
+                .allow_headers(self.allowed_headers.clone())
+                // (For the thumbnails' srcset and for revalidating.)
+                .expose_headers([
+                    header::ETAG,
+                    header::LAST_MODIFIED,
+                    header::LINK,
+                ]),
+        )
     }
-    // Stale or no if-modified-since header
-    let mut res = next.run(req).await;
-    res.headers_mut().append(
-        header::CACHE_CONTROL,
-        HeaderValue::from_static("public, no-cache"),
+
+    /// Whether pages from the origin may call the API
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|o| o == "*" || o.as_bytes() == origin.as_bytes())
+    }
+}
+
+/// Record the number of requests (by status code) and how long they
+/// took, labeled with the name of the service.
+#[instrument(skip(req, next))]
+async fn mw_metrics<B>(
+    State(service): State<&'static str>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = std::time::Instant::now();
+    let res = next.run(req).await;
+    let status = res.status().as_u16().to_string();
+    metrics::increment_counter!(
+        "gagaga_requests_total",
+        "service" => service,
+        "status" => status,
     );
-    res.headers_mut().append(
-        header::LAST_MODIFIED,
-        HeaderValue::from_str(&lmo.http())
-            .context("convert last modified to &str")
-            .map_err(ApiError::with_status(500))?,
+    metrics::histogram!(
+        "gagaga_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "service" => service,
     );
-    Ok(res.into_response())
+    res
 }
 
-/// Handle listing the directory into a JSON response
-#[instrument(err)]
-async fn api_list(
-    Chroot(chroot): Chroot,
-    VPath(vpath): VPath,
-) -> ApiResult<impl IntoResponse> {
-    /// Serialize a file's metadata into a JSON object.
-    ///
-    /// Convert the UNIX timestamp (seconds) into the difference
-    /// between the given variable epoch (also UNIX timestamp) and
-    /// each file's last modified time, with this equation:
-    /// ```
-    /// (last modified 2) = (given epoch) - (last modified)
-    /// ```
-    ///
-    /// for each file, a JSON array of four items is returned:
-    /// ```
-    /// [
-    ///     (file name, string),
-    ///     (file type, "fi" | "di" | "ln" | string),
-    ///     (file size, signed integer | null),
-    ///     (last modified 2, signed integer | null),
-    /// ]
-    /// ```
-    ///
-    /// Don't be surprised when (last modified 2) is sometimes
-    /// negative, though it should be generally positive.
+/// Metrics, in the Prometheus text format
+async fn api_metrics(
+    State(handle): State<PrometheusHandle>,
+) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+}
+
+/// Credentials for HTTP Basic authentication, set once at startup
+///
+/// Argon2 is slow on purpose, so an Authorization header that passed
+/// is remembered for a while (by a keyed hash, never as is), and only
+/// so many headers that aren't remembered are checked at once.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    /// User name
+    pub username: String,
+    /// Hash of the password, as an Argon2 PHC string (`$argon2id$...`)
+    pub password_hash: String,
+    /// Key for hashing the Authorization headers that passed (made up
+    /// at startup)
+    key: [u8; 32],
+    /// When each Authorization header that passed was checked, by
+    /// keyed hash
+    passed: Arc<Mutex<HashMap<[u8; 32], Instant>>>,
+    /// Permits to check a header that isn't remembered
+    checking: Arc<Semaphore>,
+}
+
+impl BasicAuth {
+    /// Read the credentials from `GAGAGA_AUTH_USER` and
+    /// `GAGAGA_AUTH_HASH`, if both are set.
     ///
-    /// As of version 0.4.0 of the API (version: "040"), the file type
-    /// may be only one of "fi", "di" or "ln". In the future, other
-    /// file types may be added.
-    fn serfmeta(md: &FileMetadata, epoch: i64) -> Value {
-        let name = json!(md.file_name);
-        let type_ = match md.file_type {
-            FileType::RegularFile => json!("fi"),
-            FileType::Directory => json!("di"),
-            FileType::Link => json!("ln"),
-            // Note: if other variants are later added, I will add
-            // code to handle them here.
+    /// Panics if the hash is malformed.
+    pub fn from_env() -> Option<Self> {
+        let username = std::env::var("GAGAGA_AUTH_USER").ok()?;
+        let password_hash = std::env::var("GAGAGA_AUTH_HASH").ok()?;
+        PasswordHash::new(&password_hash)
+            .expect("expect GAGAGA_AUTH_HASH to be a PHC string");
+        Some(Self::new(username, password_hash))
+    }
+
+    /// Forget everything once this many headers are remembered
+    const CAPACITY: usize = 1_000;
+    /// Check a header again after this long
+    const TTL: Duration = Duration::from_secs(60);
+    /// Check at most this many headers that aren't remembered at once
+    /// (the rest get 429 Too Many Requests)
+    const MAX_CHECKING: usize = 4;
+
+    /// Set the credentials
+    pub fn new(username: String, password_hash: String) -> Self {
+        Self {
+            username,
+            password_hash,
+            key: rand::random(),
+            passed: Default::default(),
+            checking: Arc::new(Semaphore::new(Self::MAX_CHECKING)),
+        }
+    }
+
+    /// Check the value of an Authorization header, unless it passed
+    /// recently.
+    async fn verify(self: Arc<Self>, authorization: String) -> ApiResult<bool> {
+        let digest =
+            *blake3::keyed_hash(&self.key, authorization.as_bytes()).as_bytes();
+        let known = self.passed.lock().unwrap().get(&digest).copied();
+        if known.is_some_and(|at| at.elapsed() < Self::TTL) {
+            return Ok(true);
+        }
+        let Ok(permit) = self.checking.clone().try_acquire_owned() else {
+            return Err(ApiError::with_status(429)(anyhow!(
+                "too many sign-ins being checked"
+            )));
         };
-        let size = json!(md.size);
-        let lmos = json!(md.last_modified.map(|s| epoch - s.sgnunixsec()));
-        json!([name, type_, size, lmos])
+        // Hashing is slow on purpose, so keep it off the async threads.
+        let auth = self.clone();
+        let ok = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            auth.check(&authorization)
+        })
+        .await
+        .unwrap_or(false);
+        if ok {
+            let mut passed = self.passed.lock().unwrap();
+            if passed.len() >= Self::CAPACITY {
+                passed.clear();
+            }
+            passed.insert(digest, Instant::now());
+        }
+        Ok(ok)
     }
 
-    let mut dirs = vec![];
-    let mut files = vec![];
+    /// Check the value of an Authorization header. The user name is
+    /// compared in constant time, and the password is always checked,
+    /// even if the user name is wrong.
+    fn check(&self, authorization: &str) -> bool {
+        let Some(encoded) = authorization.strip_prefix("Basic ") else {
+            return false;
+        };
+        let Ok(decoded) = BASE64_STANDARD.decode(encoded.trim()) else {
+            return false;
+        };
+        let Some((username, password)) = decoded
+            .iter()
+            .position(|&c| c == b':')
+            .map(|i| (&decoded[..i], &decoded[i + 1..]))
+        else {
+            return false;
+        };
+        let Ok(hash) = PasswordHash::new(&self.password_hash) else {
+            return false;
+        };
+        let username_ok = username.ct_eq(self.username.as_bytes());
+        let password_ok =
+            Argon2::default().verify_password(password, &hash).is_ok();
+        bool::from(username_ok) & password_ok
+    }
+}
 
-    // Measure the time now and round it down to the second
-    let now_sgnunixsec = DateTime::now().sgnunixsec();
+/// Require HTTP Basic authentication, if the credentials are set.
+/// If not, let everything through. Valid signed URLs and CORS
+/// preflights are let through too (see [`mw_signed_url`]).
+///
+/// Failures get 401 Unauthorized with a `WWW-Authenticate` challenge,
+/// and 429 Too Many Requests if too many are being checked at once
+/// (see [`BasicAuth`]).
+#[instrument(skip(req, next))]
+pub async fn mw_basic_auth<B>(
+    State(auth): State<Option<Arc<BasicAuth>>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(auth) = auth else {
+        return next.run(req).await;
+    };
+    if req.extensions().get::<SignedUrl>().is_some() {
+        return next.run(req).await;
+    }
+    // CORS preflights never carry credentials; they are answered by
+    // the CORS layer, if any (see [`CorsPolicy`]), or else turned
+    // away by the router.
+    let preflight = req.method() == http::Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        return next.run(req).await;
+    }
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let ok = match authorization {
+        Some(authorization) => match auth.verify(authorization).await {
+            Ok(ok) => ok,
+            Err(e) => return e.into_response(),
+        },
+        None => false,
+    };
+    if !ok {
+        let e = ApiError::with_status(401)(anyhow!("not authenticated"));
+        return ([(header::WWW_AUTHENTICATE, "Basic realm=\"gagaga\"")], e)
+            .into_response();
+    }
+    next.run(req).await
+}
 
-    // Read the directory
-    let stream = list_directory(&*chroot, &*vpath).await;
-    // Check if it's due to insufficient permissions
-    if let Err(e) = stream {
-        if let Ok(e) = e.downcast::<std::io::Error>() {
-            if e.kind() == std::io::ErrorKind::PermissionDenied {
-                return Err(ApiError::with_status(403)(e));
-            } else {
-                return Err(ApiError::with_status(404)(e));
+/// Marks a request as allowed by a valid signed URL
+#[derive(Debug, Clone, Copy)]
+struct SignedUrl;
+
+/// Query parameters of a signed URL (see [`crate::sign`])
+#[derive(Debug, Deserialize)]
+struct SignedQuery {
+    /// Expiry (UNIX time)
+    exp: Option<String>,
+    /// Signature
+    sig: Option<String>,
+}
+
+/// Check signed URLs (see [`crate::sign`]), if the secret is set.
+///
+/// A request with a valid signature is let through without
+/// [`mw_basic_auth`] (which must be layered inside of this). One with
+/// an invalid or expired signature gets 403 Forbidden. Requests
+/// without a signature pass on unchanged.
+#[instrument(skip(req, next))]
+pub async fn mw_signed_url<B>(
+    State(secret): State<Option<Arc<[u8]>>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(secret) = secret else {
+        return next.run(req).await;
+    };
+    let query = Query::<SignedQuery>::try_from_uri(req.uri());
+    let Ok(Query(SignedQuery {
+        exp,
+        sig: Some(sig),
+    })) = query
+    else {
+        return next.run(req).await;
+    };
+
+    let now = DateTime::now().sgnunixsec();
+    let valid = exp.and_then(|exp| exp.parse().ok()).is_some_and(|exp| {
+        verify_url(req.uri().path(), exp, &sig, &secret, now)
+    });
+    if !valid {
+        return ApiError::with_status(403)(anyhow!("bad or expired signature"))
+            .into_response();
+    }
+    req.extensions_mut().insert(SignedUrl);
+    next.run(req).await
+}
+
+/// Header with which the basic front-end proves that a request to
+/// a back-end service comes from it (see [`mw_internal_only`])
+pub const INTERNAL_SECRET_HEADER: &str = "x-gagaga-internal";
+
+/// Accept only requests that carry the shared secret (in
+/// [`INTERNAL_SECRET_HEADER`]), if it is set, so that a service meant
+/// to sit behind the basic front-end stays internal even if exposed.
+///
+/// Requests without the secret, or with a wrong one, get 403
+/// Forbidden. Without a secret, all requests pass on unchanged.
+#[instrument(skip(req, next))]
+pub async fn mw_internal_only<B>(
+    State(secret): State<Option<Arc<[u8]>>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(secret) = secret else {
+        return next.run(req).await;
+    };
+    let valid = req
+        .headers()
+        .get(INTERNAL_SECRET_HEADER)
+        .is_some_and(|value| bool::from(value.as_bytes().ct_eq(&secret)));
+    if !valid {
+        return ApiError::with_status(403)(anyhow!("internal service"))
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Limits on each client, set once at startup
+#[derive(Debug, Clone)]
+pub struct ClientPolicy {
+    /// Handle at most this many requests at once (until the response
+    /// is fully sent) from each client IP address, across all the
+    /// services that share the [`ClientLimiter`]. Requests beyond
+    /// that are turned away with 429 Too Many Requests.
+    ///
+    /// The client's address is the one found by [`mw_client_ip`]
+    /// (which decides whether to believe proxies). Behind a proxy that
+    /// isn't trusted, all the clients share the proxy's address, and
+    /// so the limit.
+    pub max_in_flight_per_ip: usize,
+    /// Don't limit clients at loopback addresses (such as the basic
+    /// front-end, calling the list service). Only set this if clients
+    /// don't come through a local proxy, or if it's trusted, or else
+    /// nobody is limited.
+    pub exempt_loopback: bool,
+}
+
+impl Default for ClientPolicy {
+    fn default() -> Self {
+        Self {
+            max_in_flight_per_ip: 16,
+            exempt_loopback: false,
+        }
+    }
+}
+
+/// Requests in flight, by client IP address
+type InFlightByIp = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Counts the requests in flight from each client, to enforce the
+/// [`ClientPolicy`] (see [`mw_limit_per_client`])
+#[derive(Debug, Clone)]
+pub struct ClientLimiter {
+    /// The limits
+    policy: Arc<ClientPolicy>,
+    /// The counts (clients with none are left out)
+    in_flight: InFlightByIp,
+}
+
+/// A request in flight from a client, counted until dropped
+#[derive(Debug)]
+struct ClientPermit {
+    /// Where it's counted
+    in_flight: InFlightByIp,
+    /// Who it's from
+    ip: IpAddr,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(n) = in_flight.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                in_flight.remove(&self.ip);
             }
         }
-        return Err(ApiError::with_status(404)(anyhow!(
-            "error building stream"
-        )));
     }
-    let mut stream = stream.unwrap();
-    while let Some(md) = stream.next().await {
-        if md.is_err() {
-            continue;
+}
+
+impl ClientLimiter {
+    /// Start counting under the given limits
+    pub fn new(policy: ClientPolicy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Count a request from the client, if it's under the limit
+    fn acquire(&self, ip: IpAddr) -> Option<ClientPermit> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let n = in_flight.entry(ip).or_default();
+        if *n >= self.policy.max_in_flight_per_ip {
+            return None;
+        }
+        *n += 1;
+        Some(ClientPermit {
+            in_flight: self.in_flight.clone(),
+            ip,
+        })
+    }
+}
+
+/// A response body that passes each chunk to a function on the way
+/// out, and is otherwise the same (so that its size hint, and with it
+/// the `Content-Length`, is kept). Anything the function owns (such
+/// as a permit) is dropped with the body, once it's sent or given up.
+struct InspectBody<F> {
+    /// The body
+    body: axum::body::BoxBody,
+    /// The function
+    inspect: F,
+}
+
+impl<F: FnMut(&bytes::Bytes) + Send + Unpin + 'static> InspectBody<F> {
+    /// Wrap the body of a response
+    fn wrap(res: Response, inspect: F) -> Response {
+        res.map(|body| axum::body::boxed(Self { body, inspect }))
+    }
+}
+
+impl<F: FnMut(&bytes::Bytes) + Send + Unpin> HttpBody for InspectBody<F> {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::result::Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            (self.inspect)(chunk);
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::result::Result<Option<http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Limit the number of requests in flight from each client IP address
+/// (see [`ClientPolicy`]), turning away the rest with
+/// 429 Too Many Requests.
+///
+/// A request is in flight until its response is fully sent, so that
+/// large downloads count. The client's address is taken from
+/// [`mw_client_ip`], which must be layered outside of this; if it
+/// can't be found, the request is let through.
+#[instrument(skip(req, next))]
+pub async fn mw_limit_per_client<B>(
+    State(limiter): State<ClientLimiter>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = req.extensions().get::<ClientIp>().map(|&ClientIp(ip)| ip);
+    let exempt =
+        |ip: &IpAddr| limiter.policy.exempt_loopback && ip.is_loopback();
+    let Some(ip) = ip.filter(|ip| !exempt(ip)) else {
+        return next.run(req).await;
+    };
+    let Some(permit) = limiter.acquire(ip) else {
+        let e = ApiError::with_status(429)(anyhow!("too many in flight"));
+        return ([(header::RETRY_AFTER, "1")], e).into_response();
+    };
+
+    // Hold on to the permit until the body is sent.
+    InspectBody::wrap(next.run(req).await, move |_| _ = &permit)
+}
+
+/// A block of IP addresses, such as `10.0.0.0/8` or `fd00::/8` (a
+/// lone address is a block of one)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    /// The first address
+    addr: IpAddr,
+    /// The number of leading bits that all the addresses share
+    prefix: u32,
+}
+
+impl IpCidr {
+    /// Tell whether the address is in the block (IPv4-mapped IPv6
+    /// addresses count as IPv4)
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // (Shifting out every bit leaves nothing to compare.)
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let shift = 32 - self.prefix;
+                u32::from(a).checked_shr(shift).unwrap_or(0)
+                    == u32::from(b).checked_shr(shift).unwrap_or(0)
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let shift = 128 - self.prefix;
+                u128::from(a).checked_shr(shift).unwrap_or(0)
+                    == u128::from(b).checked_shr(shift).unwrap_or(0)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| format!("bad address in {s:?}"))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .with_context(|| format!("bad prefix length in {s:?}"))?,
+        };
+        if prefix > max {
+            return Err(anyhow!("prefix length too long in {s:?}"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// The header that a trusted reverse proxy puts the client's address
+/// in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientIpHeader {
+    /// `X-Forwarded-For: client, proxy1, proxy2` (nginx, most others)
+    #[default]
+    XForwardedFor,
+    /// `X-Real-IP: client` (nginx's `real_ip` module)
+    XRealIp,
+    /// `Forwarded: for=client, for=proxy1` (RFC 7239)
+    Forwarded,
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "x-real-ip" => Ok(Self::XRealIp),
+            "forwarded" => Ok(Self::Forwarded),
+            _ => Err(anyhow!("unknown client IP header: {s:?}")),
+        }
+    }
+}
+
+/// Where to find the address of the client behind reverse proxies,
+/// set once at startup (see [`mw_client_ip`])
+#[derive(Debug, Clone, Default)]
+pub struct ProxyPolicy {
+    /// The proxies whose headers are believed. The header is ignored
+    /// unless the connection itself comes from one of them, so that
+    /// clients can't claim to be anyone. None by default.
+    pub trusted: Vec<IpCidr>,
+    /// The header to read
+    pub header: ClientIpHeader,
+}
+
+impl ProxyPolicy {
+    /// Tell whether the address is a trusted proxy
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Find the client's IP address: the connection's, unless that is
+    /// a trusted proxy, in which case the header is walked from the
+    /// last hop back to the first one that isn't a trusted proxy.
+    /// Unreadable hops stop the walk.
+    fn client_ip<B>(&self, req: &http::Request<B>) -> Option<IpAddr> {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_canonical())?;
+        if !self.trusts(peer) {
+            return Some(peer);
+        }
+
+        // All the hops, first to last, across repeated headers
+        let values = |name| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .collect::<Vec<_>>()
+        };
+        let hops: Vec<Option<IpAddr>> = match self.header {
+            ClientIpHeader::XForwardedFor => values("x-forwarded-for")
+                .into_iter()
+                .map(|hop| hop.parse().ok())
+                .collect(),
+            ClientIpHeader::XRealIp => values("x-real-ip")
+                .into_iter()
+                .last()
+                .map(|hop| hop.parse().ok())
+                .into_iter()
+                .collect(),
+            ClientIpHeader::Forwarded => {
+                values("forwarded").into_iter().map(forwarded_for).collect()
+            }
+        };
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            let Some(hop) = hop else { break };
+            client = hop.to_canonical();
+            if !self.trusts(client) {
+                break;
+            }
         }
-        let md = md.unwrap();
+        Some(client)
+    }
+}
+
+/// Find the address in the `for` parameter of an element of a
+/// `Forwarded` header (such as `for="[2001:db8::1]:80";proto=https`),
+/// if any
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let node = element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let node = node.trim_matches('"');
+    // [v6]:port, [v6], v4:port, or v4
+    if let Some(node) = node.strip_prefix('[') {
+        return node.split_once(']')?.0.parse().ok();
+    }
+    node.split(':').next()?.parse().ok()
+}
+
+/// The client's IP address (as an HTTP extension), as found by
+/// [`mw_client_ip`]
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Find the client's IP address (see [`ProxyPolicy`]), remember it
+/// for the rest of the handling (such as [`mw_limit_per_client`]),
+/// and log everything under it.
+///
+/// The address of the connection is only known if the server is made
+/// with [`axum::Router::into_make_service_with_connect_info`]; if it
+/// can't be found, nothing is set.
+#[instrument(skip(req, next))]
+pub async fn mw_client_ip<B>(
+    State(policy): State<Arc<ProxyPolicy>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(ip) = policy.client_ip(&req) else {
+        return next.run(req).await;
+    };
+    req.extensions_mut().insert(ClientIp(ip));
+    let span = tracing::info_span!("client", %ip);
+    next.run(req).instrument(span).await
+}
+
+/// Where to write access logs (see [`mw_access_log`])
+#[derive(Clone)]
+pub struct AccessLog(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AccessLog").finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    /// Write to standard output
+    pub fn stdout() -> Self {
+        Self::to(std::io::stdout())
+    }
+
+    /// Append to the file, creating it if need be
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open access log {path:?}"))?;
+        Ok(Self::to(std::io::LineWriter::new(file)))
+    }
+
+    /// Write to anything
+    fn to(w: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(w))))
+    }
+
+    /// Write one line (errors are only traced, not to fail requests)
+    fn write(&self, line: &str) {
+        let mut w = self.0.lock().unwrap();
+        if let Err(e) = writeln!(w, "{line}").and_then(|_| w.flush()) {
+            tracing::warn!("write access log: {e}");
+        }
+    }
+}
+
+/// Quote a field of an access log line, escaping quotes, backslashes,
+/// and anything unprintable, so that a client can't forge lines
+fn clf_quote(field: &str) -> String {
+    let mut quoted = String::with_capacity(field.len() + 2);
+    quoted.push('"');
+    for c in field.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The path and query of a URI, with the signature of a signed URL
+/// (see [`mw_signed_url`]) blotted out, so that the logs don't hand
+/// out working links
+fn redact_uri(uri: &http::Uri) -> String {
+    let path = uri.path();
+    let Some(query) = uri.query() else {
+        return path.to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("sig", _)) => "sig=REDACTED",
+            _ => pair,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{path}?{query}")
+}
+
+/// An access log line waiting for the response body to be sent, so
+/// that its size is known. Written when dropped, so that downloads
+/// cut short are logged too (with the bytes that were sent).
+struct AccessLogLine {
+    /// Where to write it
+    log: AccessLog,
+    /// Everything up to the status code
+    head: String,
+    /// The status code
+    status: u16,
+    /// Bytes of the body sent so far
+    bytes: u64,
+    /// The quoted referer and user agent
+    tail: String,
+}
+
+impl AccessLogLine {
+    /// Count a chunk of the body as sent
+    fn count(&mut self, chunk: &bytes::Bytes) {
+        self.bytes += chunk.len() as u64;
+    }
+}
+
+impl Drop for AccessLogLine {
+    fn drop(&mut self) {
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        let Self {
+            head, status, tail, ..
+        } = self;
+        self.log.write(&format!("{head} {status} {bytes} {tail}"));
+    }
+}
+
+/// Write one line per request to the access log, if set, in the
+/// Combined Log Format (as Apache and nginx do):
+///
+/// ```text
+/// 203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] "GET /a.png HTTP/1.1" 200 2326 "-" "curl/8.0"
+/// ```
+///
+/// The client's address is taken from [`mw_client_ip`], if it ran
+/// (outside of this), or else from the connection. The size is that
+/// of the body as actually sent, so it is only known, and the line
+/// only written, once the body is done. Signatures of signed URLs are
+/// left out (see [`redact_uri`]).
+#[instrument(skip(req, next))]
+pub async fn mw_access_log<B>(
+    State(log): State<Option<AccessLog>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(log) = log else {
+        return next.run(req).await;
+    };
+
+    // Gather everything about the request before it's gone
+    let host = req
+        .extensions()
+        .get::<ClientIp>()
+        .map(|&ClientIp(ip)| ip)
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        })
+        .map_or("-".to_string(), |ip| ip.to_string());
+    let request = format!(
+        "{} {} {:?}",
+        req.method(),
+        redact_uri(req.uri()),
+        req.version()
+    );
+    let head = format!(
+        "{host} - - [{}] {}",
+        DateTime::now().clf(),
+        clf_quote(&request)
+    );
+    let quoted = |name| {
+        req.headers().get(name).map_or("\"-\"".to_string(), |v| {
+            clf_quote(&String::from_utf8_lossy(v.as_bytes()))
+        })
+    };
+    let tail =
+        format!("{} {}", quoted(header::REFERER), quoted(header::USER_AGENT));
+
+    // Count the body as it goes out
+    let res = next.run(req).await;
+    let mut line = AccessLogLine {
+        log,
+        head,
+        status: res.status().as_u16(),
+        bytes: 0,
+        tail,
+    };
+    InspectBody::wrap(res, move |chunk| line.count(chunk))
+}
+
+/// Rules for thumbnailing, set once at startup
+#[derive(Debug, Clone)]
+pub struct ThumbPolicy {
+    /// Make at most this many thumbnails at once (for the whole
+    /// server). Requests beyond that are turned away with
+    /// 429 Too Many Requests.
+    pub max_in_flight: usize,
+    /// Resample with this filter, if set. If not, a fast filter is
+    /// used. The higher-quality filters (Catmull-Rom, Lanczos) cost
+    /// several times the CPU per thumbnail, which matters most for
+    /// large photos; consider lowering `max_in_flight` with them.
+    pub filter: Option<Resample>,
+    /// Sharpen thumbnails after downscaling with this unsharp mask
+    /// (off by default, so that the output is as it always was)
+    pub sharpen: Option<Sharpen>,
+    /// Tell whether a client's thumbnail is still fresh by a hash of
+    /// the content of the image (BLAKE3, as the entity tag), made
+    /// anew on every request, instead of by its size and last
+    /// modified time, which some tools (such as `rsync --checksum`
+    /// or backup restores) keep or reset regardless of the content.
+    /// Off by default, since each request then reads the whole image
+    /// (local files only).
+    pub content_etag: bool,
+    /// Limits on animated thumbnails (asked for with `?animate=true`)
+    pub animation: AnimLimits,
+    /// Read images this many bytes at a time (64 KiB by default)
+    pub chunk_size: usize,
+    /// Limits on decoding images; those over them are thumbnailed as
+    /// a broken image icon
+    pub decode: DecodeLimits,
+    /// Decode, resample and encode images on this pool (shared by
+    /// clones of the policy), of one thread per physical core by
+    /// default
+    pub pool: CpuPool,
+}
+
+impl Default for ThumbPolicy {
+    fn default() -> Self {
+        Self {
+            max_in_flight: std::thread::available_parallelism()
+                .map_or(4, |n| n.get()),
+            filter: None,
+            sharpen: None,
+            content_etag: false,
+            animation: AnimLimits::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            decode: DecodeLimits::default(),
+            pool: CpuPool::default(),
+        }
+    }
+}
+
+/// The [`ThumbPolicy`] (as an HTTP extension)
+#[derive(Debug, Clone)]
+struct ThumbSettings(Arc<ThumbPolicy>);
+
+/// Allow ThumbSettings to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for ThumbSettings {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        let settings = parts
+            .extensions
+            .get::<ThumbSettings>()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("thumb policy not set"))
+            })
+            .map(|settings| settings.clone())?;
+        Ok(settings)
+    }
+}
+
+/// Set the ThumbSettings in the request
+#[instrument(skip(req, next))]
+async fn mw_set_thumb_settings<B>(
+    State(policy): State<Arc<ThumbPolicy>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(ThumbSettings(policy));
+    next.run(req).await
+}
+
+/// Limit the number of requests being handled at once to the number
+/// of permits of the semaphore, turning away the rest with
+/// 429 Too Many Requests.
+#[instrument(skip(req, next))]
+async fn mw_limit_in_flight<B>(
+    State(permits): State<Arc<Semaphore>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Ok(_permit) = permits.try_acquire() else {
+        let e = ApiError::with_status(429)(anyhow!("too many in flight"));
+        return ([(header::RETRY_AFTER, "1")], e).into_response();
+    };
+    next.run(req).await
+}
+
+/// Query parameters accepted by the thumbnail API
+#[derive(Debug, Deserialize)]
+struct ThumbQuery {
+    /// `fit` (default) or `cover` (see [`ThumbMode`])
+    mode: Option<String>,
+    /// Device pixel ratio: 1 (default), 2, or 3. The thumbnail's box
+    /// is scaled by this, for HiDPI displays.
+    dpr: Option<u32>,
+    /// `color` to get the average color of the image, as
+    /// `{"color": "#rrggbb"}`, instead of the thumbnail (for painting
+    /// a tile before the thumbnail loads)
+    meta: Option<String>,
+    /// The version of the image (see [`file_version`]). If it's
+    /// current, the thumbnail may be cached for good (see
+    /// [`mw_thumb_version`]).
+    v: Option<String>,
+    /// `true` to keep animated GIF and WebP images moving, as an
+    /// animated GIF (see [`ithumbanim`]). Still images, and those
+    /// that can't be animated within the limits, get a still
+    /// thumbnail anyway.
+    animate: Option<bool>,
+}
+
+/// A thumbnailer for some box and quality
+type Thumbnailer = fn(
+    &[u8],
+    ThumbMode,
+    Option<Resample>,
+    Option<Sharpen>,
+    DecodeLimits,
+) -> Result<Vec<u8>>;
+
+/// Supported device pixel ratios, and the thumbnailers for them.
+/// ::<width, height, quality%>
+const THUMB_DPRS: [(u32, Thumbnailer); 3] = [
+    (1, ithumbjpg::<16, 16, 50>),
+    (2, ithumbjpg::<32, 32, 50>),
+    (3, ithumbjpg::<48, 48, 50>),
+];
+
+/// An animated thumbnailer for some box (see [`ithumbanim`])
+type AnimThumbnailer = fn(
+    &[u8],
+    ThumbMode,
+    Option<Resample>,
+    AnimLimits,
+    DecodeLimits,
+) -> Result<Option<Vec<u8>>>;
+
+/// Animated thumbnailers for the supported device pixel ratios (the
+/// same boxes as [`THUMB_DPRS`])
+const ANIM_THUMB_DPRS: [(u32, AnimThumbnailer); 3] = [
+    (1, ithumbanim::<16, 16>),
+    (2, ithumbanim::<32, 32>),
+    (3, ithumbanim::<48, 48>),
+];
+
+/// Icon served in place of the thumbnail of a file that can't be
+/// decoded as an image (an unsupported format, or a corrupt file)
+const BROKEN_IMAGE_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16" width="16" height="16">
+<rect x="1.5" y="2.5" width="13" height="11" rx="1" fill="#eee" stroke="#888"/>
+<path d="M3 12l3-4 2 2.5 2-1.5 3 3z" fill="#bbb"/>
+<circle cx="11" cy="5.5" r="1.2" fill="#bbb"/>
+<path d="M2 15L14 1" stroke="#c33" stroke-width="1.5"/>
+</svg>
+"##;
+
+/// Make a `Link` header value listing the thumbnail at every
+/// supported device pixel ratio, so that clients can build a
+/// `srcset` out of it
+fn thumb_srcset_link(
+    path: &str,
+    mode: Option<&str>,
+    version: Option<&str>,
+) -> String {
+    let mode = mode.map_or(String::new(), |mode| format!("&mode={mode}"));
+    let version = version.map_or(String::new(), |v| format!("&v={v}"));
+    THUMB_DPRS
+        .iter()
+        .map(|(dpr, _)| {
+            format!(
+                "<{path}?dpr={dpr}{mode}{version}>; rel=\"alternate\"; \
+                type=\"image/jpeg\"; title=\"{dpr}x\""
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Read a whole (image) file, of at most (N) MB, in chunks of (about)
+/// `chunk_size` bytes
+///
+/// Files known (by their metadata) to be larger than that are
+/// rejected before being opened at all. Either way, larger files are
+/// not found.
+async fn read_image<const LIMITMB: usize>(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    chunk_size: usize,
+) -> ApiResult<BytesMut> {
+    // Check the size first, if known
+    let limit = (LIMITMB * 1024 * 1024) as u64;
+    let size = backend
+        .read_metadata(chroot, vpath)
+        .await
+        .ok()
+        .and_then(|md| md.size);
+    if size.is_some_and(|size| size > limit) {
+        return Err(ApiError::with_status(404)(anyhow!("file too large")));
+    }
+
+    // Open file, read file, check length (again, in case the size was
+    // not known or the file grew)
+    let mut file = backend
+        .open_file(chroot, vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    // +1 is to detect over-reading.
+    let cap = LIMITMB * 1024 * 1024 + 1;
+    let mut buf = BytesMut::with_capacity(size.map_or(chunk_size, |size| {
+        // (+1 to see the end of the file without growing.)
+        size as usize + 1
+    }));
+    loop {
+        buf.reserve(chunk_size);
+        let n = file
+            .read_buf(&mut buf)
+            .await
+            .context("read file")
+            .map_err(ApiError::with_status(404))?;
+        if n == 0 {
+            break;
+        }
+        if buf.len() > cap {
+            return Err(ApiError::with_status(404)(anyhow!("file too large")));
+        }
+    }
+    Ok(buf)
+}
+
+/// Let a versioned thumbnail (with `?v=`) be cached for good
+/// ([`CachePolicy::Immutable`]), if the version is that of the image
+/// as it is now (see [`file_version`]). Any other version is served
+/// like an unversioned thumbnail, and so revalidated.
+#[instrument(skip(req, next))]
+async fn mw_thumb_version(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    req: http::Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let version = Query::<ThumbQuery>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(query)| query.v);
+    let Some(version) = version else {
+        return next.run(req).await;
+    };
+    let current =
+        match (vpath.to_str(), backend.read_metadata(&chroot, &vpath).await) {
+            (Some(path), Ok(md)) => {
+                let mtime = md.last_modified.map(|lmo| lmo.sgnunixsec());
+                Some(file_version(path, md.size, mtime))
+            }
+            _ => None,
+        };
+
+    let mut res = next.run(req).await;
+    if current.as_deref() == Some(&*version) && res.status().is_success() {
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            CachePolicy::Immutable.header_value(),
+        );
+    }
+    res
+}
+
+/// Thumbnail API
+///
+/// Thumbnail a file with a maximum tolerance of reading (N) MB (see
+/// [`read_image`]).
+///
+/// See [`ThumbQuery`] for the options. The response (if a still
+/// JPEG) links to the thumbnail at every supported device pixel ratio
+/// (in `Link`).
+///
+/// With `?meta=color`, the average color is computed (see
+/// [`iavgcolor`]) instead, and only then.
+///
+/// A file that is there but can't be decoded as an image gets a
+/// generic icon ([`BROKEN_IMAGE_SVG`]) instead of a thumbnail, or
+/// 415 Unsupported Media Type instead of a color; only a missing (or
+/// too large) file gets 404.
+#[instrument(err)]
+async fn api_thumb<const LIMITMB: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    ThumbSettings(policy): ThumbSettings,
+    uri: http::Uri,
+    Query(query): Query<ThumbQuery>,
+) -> ApiResult<Response> {
+    // Decide on the options before doing any work
+    let color = match query.meta.as_deref() {
+        None => false,
+        Some("color") => true,
+        Some(meta) => {
+            return Err(ApiError::with_status(400)(anyhow!(
+                "unsupported meta: {meta:?}"
+            )))
+        }
+    };
+    let mode = query
+        .mode
+        .as_deref()
+        .map(ThumbMode::from_str)
+        .transpose()
+        .map_err(ApiError::with_status(400))?
+        .unwrap_or_default();
+    let dpr = query.dpr.unwrap_or(1);
+    let thumbnailer = THUMB_DPRS
+        .iter()
+        .find_map(|&(d, thumbnailer)| (d == dpr).then_some(thumbnailer))
+        .ok_or_else(|| {
+            ApiError::with_status(400)(anyhow!("unsupported dpr: {dpr}"))
+        })?;
+
+    let buf =
+        read_image::<LIMITMB>(&*backend, &chroot, &vpath, policy.chunk_size)
+            .await?
+            .freeze();
+    let decode = policy.decode;
+
+    // Or, average color
+    if color {
+        let color = policy
+            .pool
+            .run(move || iavgcolor(&buf, decode))
+            .await
+            .context("spawn color task")
+            .map_err(ApiError::with_status(500))?
+            .context("average color")
+            .map_err(ApiError::with_status(415))?;
+        return Ok((
+            [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+            json!({ "color": color }).to_string(),
+        )
+            .into_response());
+    }
+
+    // Or, animated, if it can be (or else still, as usual)
+    let animator = ANIM_THUMB_DPRS
+        .iter()
+        .find_map(|&(d, animator)| (d == dpr).then_some(animator))
+        .filter(|_| query.animate.unwrap_or(false));
+    if let Some(animator) = animator {
+        let (buf, filter, limits) =
+            (buf.clone(), policy.filter, policy.animation);
+        let gif = policy
+            .pool
+            .run(move || animator(&buf, mode, filter, limits, decode))
+            .await
+            .context("spawn animated thumbnailing task")
+            .map_err(ApiError::with_status(500))?;
+        match gif {
+            Ok(Some(gif)) => {
+                return Ok((thumb_headers("image/gif", gif.len()), gif)
+                    .into_response())
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::debug!("animated thumbnailing (serve still): {e:#}")
+            }
+        }
+    }
+
+    // Make thumbnail
+    let (filter, sharpen) = (policy.filter, policy.sharpen);
+    let jpg = policy
+        .pool
+        .run(move || thumbnailer(&buf, mode, filter, sharpen, decode))
+        .await
+        .context("spawn thumbnailing task")
+        .map_err(ApiError::with_status(500))?;
+    let jpg = match jpg {
+        Ok(jpg) => jpg,
+        Err(e) => {
+            tracing::debug!("thumbnailing (serve icon): {e:#}");
+            return Ok((
+                thumb_headers("image/svg+xml", BROKEN_IMAGE_SVG.len()),
+                BROKEN_IMAGE_SVG,
+            )
+                .into_response());
+        }
+    };
+
+    // Response
+    let link = thumb_srcset_link(
+        uri.path(),
+        query.mode.as_deref(),
+        query.v.as_deref(),
+    );
+    Ok((
+        thumb_headers("image/jpeg", jpg.len()),
+        [(header::LINK, link)],
+        jpg,
+    )
+        .into_response())
+}
+
+/// Headers for a thumbnail that is wholly in memory: its type, its exact
+/// length (so that caches and clients know it up front), and that ranges
+/// aren't served
+fn thumb_headers(
+    content_type: &'static str,
+    len: usize,
+) -> [(http::HeaderName, HeaderValue); 3] {
+    [
+        (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+        (header::CONTENT_LENGTH, HeaderValue::from(len)),
+        (header::ACCEPT_RANGES, HeaderValue::from_static("none")),
+    ]
+}
+
+/// The thumbnailing permits (as an HTTP extension), for the
+/// handlers that make several thumbnails per request
+#[derive(Debug, Clone)]
+struct ThumbPermits(Arc<Semaphore>);
+
+/// Allow ThumbPermits to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for ThumbPermits {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts
+            .extensions
+            .get::<ThumbPermits>()
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("thumb permits not set"))
+            })
+    }
+}
+
+/// Set the ThumbPermits in the request
+#[instrument(skip(req, next))]
+async fn mw_set_thumb_permits<B>(
+    State(permits): State<Arc<Semaphore>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(ThumbPermits(permits));
+    next.run(req).await
+}
+
+/// Make one thumbnail of a batch (see [`api_thumbs`])
+async fn thumb_one<const LIMITMB: usize>(
+    backend: &dyn OpenFile,
+    visibility: &Visibility,
+    chroot: &RealPath,
+    permits: &Semaphore,
+    policy: &ThumbPolicy,
+    (vpath, mode, thumbnailer): (&str, ThumbMode, Thumbnailer),
+) -> ApiResult<Vec<u8>> {
+    // The paths are checked as the guard would check them.
+    let vpath =
+        check_virt_path(backend, visibility, chroot, vpath.as_ref()).await?;
+
+    // Wait (rather than turn away) for a permit, since the batch as a
+    // whole has already been accepted. Take it before reading, so
+    // that the permits bound the images held in memory, too.
+    let _permit = permits
+        .acquire()
+        .await
+        .context("acquire permit")
+        .map_err(ApiError::with_status(500))?;
+    let buf = read_image::<LIMITMB>(backend, chroot, &vpath, policy.chunk_size)
+        .await?;
+    let (filter, sharpen, decode) =
+        (policy.filter, policy.sharpen, policy.decode);
+    let jpg = policy
+        .pool
+        .run(move || thumbnailer(&buf, mode, filter, sharpen, decode))
+        .await
+        .context("spawn thumbnailing task")
+        .map_err(ApiError::with_status(500))?
+        .context("thumbnailing")
+        .map_err(ApiError::with_status(415))?;
+    Ok(jpg)
+}
+
+/// Bulk thumbnail API
+///
+/// Thumbnail up to (N) files at once, each of at most (M) MB, given as
+/// a JSON array of virtual paths in the body. The
+/// options (`mode` and `dpr`; see [`ThumbQuery`]) apply to all of
+/// them. Larger batches are rejected with 413 Payload Too Large.
+///
+/// The response lists, in the order asked for, either the thumbnail
+/// (base64-encoded JPEG) or the status code of the error (such as 404
+/// for a missing file, or 415 for one that can't be decoded), for
+/// each path; a failing path does not fail the rest:
+///
+/// ```json
+/// {"thumbs": [{"path": "/a.png", "jpeg": "/9j/4AAQ..."},
+///             {"path": "/b.txt", "error": 404}]}
+/// ```
+#[instrument(err, skip(paths))]
+async fn api_thumbs<const MAXBATCH: usize, const LIMITMB: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    ThumbSettings(policy): ThumbSettings,
+    ThumbPermits(permits): ThumbPermits,
+    Query(query): Query<ThumbQuery>,
+    axum::Json(paths): axum::Json<Vec<String>>,
+) -> ApiResult<Response> {
+    // Decide on the options before doing any work
+    if paths.len() > MAXBATCH {
+        return Err(ApiError::with_status(413)(anyhow!(
+            "too many paths: {} > {MAXBATCH}",
+            paths.len()
+        )));
+    }
+    if let Some(meta) = query.meta.as_deref() {
+        return Err(ApiError::with_status(400)(anyhow!(
+            "unsupported meta: {meta:?}"
+        )));
+    }
+    let mode = query
+        .mode
+        .as_deref()
+        .map(ThumbMode::from_str)
+        .transpose()
+        .map_err(ApiError::with_status(400))?
+        .unwrap_or_default();
+    let dpr = query.dpr.unwrap_or(1);
+    let thumbnailer = THUMB_DPRS
+        .iter()
+        .find_map(|&(d, thumbnailer)| (d == dpr).then_some(thumbnailer))
+        .ok_or_else(|| {
+            ApiError::with_status(400)(anyhow!("unsupported dpr: {dpr}"))
+        })?;
+
+    // Thumbnail (several at once, keeping the order)
+    let visibility = visibility_or_default(visibility);
+    let max_in_flight = policy.max_in_flight.max(1);
+    let thumbs = tokio_stream::iter(paths).map(|path| {
+        let (backend, visibility) = (backend.clone(), visibility.clone());
+        let (chroot, permits) = (chroot.clone(), permits.clone());
+        let policy = policy.clone();
+        async move {
+            let item = (path.as_str(), mode, thumbnailer);
+            let thumb = thumb_one::<LIMITMB>(
+                &*backend,
+                &visibility,
+                &chroot,
+                &permits,
+                &policy,
+                item,
+            )
+            .await;
+            match thumb {
+                Ok(jpg) => {
+                    let jpg = BASE64_STANDARD.encode(jpg);
+                    json!({ "path": path, "jpeg": jpg })
+                }
+                Err(e) => {
+                    tracing::debug!("thumbnail {path:?}: {e}");
+                    json!({ "path": path, "error": e.0.as_u16() })
+                }
+            }
+        }
+    });
+    let thumbs = futures_util::StreamExt::buffered(thumbs, max_in_flight)
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        json!({ "thumbs": thumbs }).to_string(),
+    )
+        .into_response())
+}
+
+/// BlurHashes of images, remembered by virtual path along with the
+/// size and last modified time that they were made for
+#[derive(Debug, Clone, Default)]
+struct BlurHashes(Arc<Mutex<HashMap<PathBuf, (FileMetadata, String)>>>);
+
+impl BlurHashes {
+    /// Forget everything once this many images are remembered
+    const CAPACITY: usize = 10_000;
+}
+
+/// Allow BlurHashes to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for BlurHashes {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts
+            .extensions
+            .get::<BlurHashes>()
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("blurhashes not set"))
+            })
+    }
+}
+
+/// Set the BlurHashes in the request
+#[instrument(skip(req, next))]
+async fn mw_set_blurhashes<B>(
+    State(hashes): State<BlurHashes>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(hashes);
+    next.run(req).await
+}
+
+/// BlurHash API
+///
+/// Make a [BlurHash](https://blurha.sh) of an image (read as with the
+/// thumbnail API, with a maximum tolerance of (N) MB), a compact
+/// string that clients can render into a blurry placeholder while the
+/// thumbnail loads. The response looks like `{"blurhash": "..."}`.
+///
+/// An image that can't be decoded (or is over the decoding limits)
+/// gets 415 Unsupported Media Type, as with average colors.
+///
+/// BlurHashes are remembered until the image changes (judging by its
+/// size and last modified time).
+#[instrument(err, skip(hashes))]
+async fn api_blurhash<const LIMITMB: usize>(
+    hashes: BlurHashes,
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    ThumbSettings(policy): ThumbSettings,
+) -> ApiResult<impl IntoResponse> {
+    let md = backend
+        .read_metadata(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let known = hashes.0.lock().unwrap().get(&*vpath).cloned();
+    let hash = match known {
+        Some((known, hash))
+            if known.size == md.size
+                && known.last_modified == md.last_modified =>
+        {
+            hash
+        }
+        _ => {
+            let buf = read_image::<LIMITMB>(
+                &*backend,
+                &chroot,
+                &vpath,
+                policy.chunk_size,
+            )
+            .await?;
+            let decode = policy.decode;
+            let hash = policy
+                .pool
+                .run(move || iblurhash(&buf, decode))
+                .await
+                .context("spawn blurhash task")
+                .map_err(ApiError::with_status(500))?
+                .context("blurhash")
+                .map_err(ApiError::with_status(415))?;
+            let mut hashes = hashes.0.lock().unwrap();
+            if hashes.len() >= BlurHashes::CAPACITY {
+                hashes.clear();
+            }
+            hashes.insert(vpath.to_path_buf(), (md, hash.clone()));
+            hash
+        }
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        json!({ "blurhash": hash }).to_string(),
+    ))
+}
+
+/// Dimensions API
+///
+/// Report the width and height of an image (JPEG, PNG, GIF or WebP)
+/// as `{"width": W, "height": H}`, reading only as much of the file
+/// as it takes to find them in the header, and no more than (N) MB,
+/// as with the thumbnail API. Anything else is not found.
+#[instrument(err)]
+async fn api_dimensions<const LIMITMB: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+) -> ApiResult<impl IntoResponse> {
+    // Check the size first, if known
+    let limit = LIMITMB * 1024 * 1024;
+    let size = backend
+        .read_metadata(&chroot, &vpath)
+        .await
+        .ok()
+        .and_then(|md| md.size);
+    if size.is_some_and(|size| size > limit as u64) {
+        return Err(ApiError::with_status(404)(anyhow!("file too large")));
+    }
+
+    // Read a little at a time (doubling each time), looking for the
+    // dimensions after each step, until they are found or the file
+    // (or the limit) runs out.
+    let mut file = backend
+        .open_file(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let mut buf = BytesMut::new();
+    let mut want: usize = 16 * 1024;
+    loop {
+        buf.reserve(want.saturating_sub(buf.len()));
+        let n = file
+            .read_buf(&mut buf)
+            .await
+            .context("read file")
+            .map_err(ApiError::with_status(404))?;
+        if n != 0 && buf.len() < want.min(limit) {
+            continue;
+        }
+        match idimensions(&buf) {
+            Ok((width, height)) => {
+                let value = json!({ "width": width, "height": height });
+                return Ok((
+                    [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+                    value.to_string(),
+                ));
+            }
+            Err(e) if n == 0 || buf.len() >= limit => {
+                return Err(ApiError::with_status(404)(e));
+            }
+            Err(_) => want *= 2,
+        }
+    }
+}
+
+/// How long clients may use a cached response without asking again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CachePolicy {
+    /// Revalidate each time the cache is used (`no-cache`)
+    Revalidate,
+    /// Fresh for this many seconds. After that, a stale response may
+    /// still be used for as long again while it's being revalidated.
+    MaxAge(u32),
+    /// Fresh for a year, and never revalidated (for URLs whose
+    /// content never changes)
+    Immutable,
+}
+
+impl CachePolicy {
+    /// The `Cache-Control` directive
+    fn header_value(&self) -> HeaderValue {
+        match self {
+            Self::Revalidate => HeaderValue::from_static("public, no-cache"),
+            Self::MaxAge(n) => HeaderValue::from_str(&format!(
+                "public, max-age={n}, stale-while-revalidate={n}"
+            ))
+            .expect("expect the directive to be a valid header value"),
+            Self::Immutable => {
+                HeaderValue::from_static("public, max-age=31536000, immutable")
+            }
+        }
+    }
+}
+
+/// Set `Cache-Control` on successful (and 304) responses according to
+/// the policy, unless set already.
+#[instrument(skip(req, next))]
+async fn mw_cache_control<B>(
+    State(policy): State<CachePolicy>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut res = next.run(req).await;
+    if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+        res.headers_mut()
+            .entry(header::CACHE_CONTROL)
+            .or_insert_with(|| policy.header_value());
+    }
+    res
+}
+
+/// HTTP caching for files and directories in general by comparing
+/// If-Modified-Since (only). How long the client may go without
+/// revalidating is up to [`mw_cache_control`].
+#[instrument(skip(req, next), err)]
+async fn mw_cache_http_reval_lmo(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    req: http::Request<Body>,
+    next: Next<Body>,
+) -> ApiResult<Response> {
+    // Read the metadata from the file system and its last modified -> lmo
+    let md = backend.read_metadata(&chroot, &vpath).await;
+    let md = match md {
+        Ok(md) => md,
+        Err(e) => {
+            tracing::warn!("read_metadata: {e:?}");
+            return Ok(next.run(req).await);
+        }
+    };
+    let lmo = md.last_modified;
+    if lmo.is_none() {
+        tracing::trace!("no last modified for virtual path {vpath:?}");
+        return Ok(next.run(req).await);
+    }
+    let lmo = lmo.unwrap();
+    tracing::trace!("could read last modified from the file system");
+    // NOTE: Once I have the last modified date from the file system,
+    // I can send Last-Modified.
+
+    // Get HTTP Last Modified date from the client
+    // (If-Modified-Since) -> hmo
+    let hmo = req.headers().get(header::IF_MODIFIED_SINCE);
+    if let Some(hmo) = hmo {
+        tracing::trace!("client sent if-modified-since");
+        let hmo = hmo
+            .to_str()
+            .context("convert if-modified-since to &str")
+            .map_err(ApiError::with_status(400))?;
+        let hmo = DateTime::from_http(hmo)
+            .context("convert &str if-modified-since to DateTime")
+            .map_err(ApiError::with_status(400))?;
+        // If lmo is earlier than hmo, or equal, then fresh.
+        if lmo.seccmp(&hmo).is_le() {
+            tracing::trace!("fresh");
+            return Ok(StatusCode::NOT_MODIFIED.into_response());
+        }
+        tracing::trace!("stale");
+    } else {
+        tracing::trace!("no if-modified-since header from client");
+    }
+    // Stale or no if-modified-since header
+    let mut res = next.run(req).await;
+    res.headers_mut().append(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&lmo.http())
+            .context("convert last modified to &str")
+            .map_err(ApiError::with_status(500))?,
+    );
+    Ok(res.into_response())
+}
+
+/// Serialize a file type into a JSON string ("fi", "di" or "ln")
+fn sertype(file_type: FileType) -> Value {
+    match file_type {
+        FileType::RegularFile => json!("fi"),
+        FileType::Directory => json!("di"),
+        FileType::Link => json!("ln"),
+        // Note: if other variants are later added, I will add
+        // code to handle them here.
+    }
+}
+
+/// Serialize a file's metadata into a JSON object.
+///
+/// Convert the UNIX timestamp (seconds) into the difference
+/// between the given variable epoch (also UNIX timestamp) and
+/// each file's last modified time, with this equation:
+/// ```
+/// (last modified 2) = (given epoch) - (last modified)
+/// ```
+///
+/// for each file, a JSON array of four items is returned:
+/// ```
+/// [
+///     (file name, string),
+///     (file type, "fi" | "di" | "ln" | string),
+///     (file size, signed integer | null),
+///     (last modified 2, signed integer | null),
+/// ]
+/// ```
+///
+/// Don't be surprised when (last modified 2) is sometimes
+/// negative, though it should be generally positive.
+///
+/// As of version 0.4.0 of the API (version: "040"), the file type
+/// may be only one of "fi", "di" or "ln". In the future, other
+/// file types may be added.
+pub(crate) fn serfmeta(md: &FileMetadata, epoch: i64) -> Value {
+    let name = json!(md.file_name);
+    let type_ = sertype(md.file_type);
+    let size = json!(md.size);
+    let lmos = json!(md.last_modified.map(|s| epoch - s.sgnunixsec()));
+    json!([name, type_, size, lmos])
+}
+
+/// Serialize a file's metadata into a JSON object, for versions
+/// "041" and later of the list API.
+///
+/// It carries the same information as [`serfmeta`], but by name:
+/// ```
+/// {
+///     "name": (file name, string),
+///     "type": (file type, "fi" | "di" | "ln" | string),
+///     "size": (file size, signed integer | null),
+///     "age": (last modified 2, signed integer | null),
+/// }
+/// ```
+///
+/// If the name isn't UTF-8 (and the [`ListPolicy`] lets it be listed
+/// anyway), there is also `"raw"`, the raw name, percent-encoded.
+///
+/// Since "042", there is also `"mode"`, the permission bits (such as
+/// 493 for `0o755`), or null if unknown.
+///
+/// Since "043", there are also `"created"` and `"accessed"`, the
+/// ages of the creation and last access times (like `"age"`), or null
+/// if unknown.
+///
+/// Since "046", links that were to be followed but couldn't be have
+/// `"broken"`, either `"loop"` or `"missing"` (added by the list API
+/// itself; see [`broken_link`]).
+fn serfmeta_obj(md: &FileMetadata, epoch: i64, version: ListVersion) -> Value {
+    let mut value = json!({
+        "name": md.file_name,
+        "type": sertype(md.file_type),
+        "size": md.size,
+        "age": md.last_modified.map(|s| epoch - s.sgnunixsec()),
+    });
+    let age = |dt: Option<DateTime>| dt.map(|s| epoch - s.sgnunixsec());
+    if let Some(raw) = &md.raw_name {
+        value["raw"] = json!(percent_encode(raw, NON_ALPHANUMERIC).to_string());
+    }
+    if version >= ListVersion::V042 {
+        value["mode"] = json!(md.mode);
+    }
+    if version >= ListVersion::V043 {
+        value["created"] = json!(age(md.created));
+        value["accessed"] = json!(age(md.accessed));
+    }
+    if version >= ListVersion::V047 && md.file_type != FileType::Directory {
+        value["category"] = json!(Category::of(&md.file_name));
+    }
+    value
+}
+
+/// Broad kind of a file (for grouping), by the MIME type guessed from
+/// its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Category {
+    /// Pictures
+    Image,
+    /// Movies
+    Video,
+    /// Sound
+    Audio,
+    /// Text, PDFs, office documents, e-books
+    Document,
+    /// Archives and compressed files
+    Archive,
+    /// Source code, markup, and data formats (such as JSON)
+    Code,
+    /// Anything else, or unknown
+    Other,
+}
+
+impl Category {
+    /// Classify a file by its name
+    fn of(name: &str) -> Self {
+        let Some(mime) = mime_guess::from_path(name).first() else {
+            return Self::Other;
+        };
+        let subtype = mime.subtype().as_str();
+        match mime.type_().as_str() {
+            "image" => Self::Image,
+            "video" => Self::Video,
+            "audio" => Self::Audio,
+            "text" => match subtype {
+                "plain" | "markdown" | "csv" | "rtf" => Self::Document,
+                _ => Self::Code,
+            },
+            "application" => match subtype {
+                "zip" | "gzip" | "x-gzip" | "x-tar" | "x-gtar" | "x-bzip"
+                | "x-bzip2" | "x-xz" | "x-7z-compressed"
+                | "x-rar-compressed" | "vnd.rar" | "zstd" | "java-archive" => {
+                    Self::Archive
+                }
+                "pdf" | "msword" | "rtf" | "epub+zip" | "vnd.ms-excel"
+                | "vnd.ms-powerpoint" => Self::Document,
+                s if s.starts_with("vnd.openxmlformats-officedocument.")
+                    || s.starts_with("vnd.oasis.opendocument.") =>
+                {
+                    Self::Document
+                }
+                "javascript" | "x-javascript" | "json" | "xml" | "x-sh"
+                | "x-csh" | "toml" | "x-httpd-php" | "xhtml+xml" => Self::Code,
+                _ => Self::Other,
+            },
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Version of the schema of the list API's response
+///
+/// The client picks one with the `Accept-Version` header or the
+/// `apiver` query parameter (which wins). If neither is given, it's
+/// "040".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+enum ListVersion {
+    /// Entries are arrays (see [`serfmeta`])
+    #[default]
+    V040,
+    /// Entries are objects (see [`serfmeta_obj`])
+    V041,
+    /// Like "041", with the permission bits
+    V042,
+    /// Like "042", with the creation and last access times
+    V043,
+    /// Like "043", with the ancestry of the directory (see
+    /// [`ancestry`])
+    V044,
+    /// Like "044", with the URL of the parent directory as `"parent"`
+    /// (absent at the root)
+    V045,
+    /// Like "045", saying why links that can't be followed are broken
+    /// (see [`broken_link`])
+    V046,
+    /// Like "046", with the broad kind of each file as `"category"`
+    /// (see [`Category`])
+    V047,
+}
+
+impl FromStr for ListVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "040" => Ok(Self::V040),
+            "041" => Ok(Self::V041),
+            "042" => Ok(Self::V042),
+            "043" => Ok(Self::V043),
+            "044" => Ok(Self::V044),
+            "045" => Ok(Self::V045),
+            "046" => Ok(Self::V046),
+            "047" => Ok(Self::V047),
+            _ => Err(anyhow!("unsupported version: {s:?}")),
+        }
+    }
+}
+
+impl ListVersion {
+    /// The version string, as in the `version` field
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::V040 => "040",
+            Self::V041 => "041",
+            Self::V042 => "042",
+            Self::V043 => "043",
+            Self::V044 => "044",
+            Self::V045 => "045",
+            Self::V046 => "046",
+            Self::V047 => "047",
+        }
+    }
+
+    /// Serialize a file's metadata according to the version
+    fn serfmeta(&self, md: &FileMetadata, epoch: i64) -> Value {
+        match self {
+            Self::V040 => serfmeta(md, epoch),
+            Self::V041
+            | Self::V042
+            | Self::V043
+            | Self::V044
+            | Self::V045
+            | Self::V046
+            | Self::V047 => serfmeta_obj(md, epoch, *self),
+        }
+    }
+}
+
+/// List the directories from the root down to the virtual path (both
+/// included) as `[name, url]` pairs, where the URL is the
+/// (percent-encoded) path of the directory on this server. The root
+/// is named `"/"`.
+///
+/// For example, `a/b` gives:
+/// `[["/", "/"], ["a", "/a"], ["b", "/a/b"]]`.
+fn ancestry(vpath: &VirtualPath) -> Value {
+    let mut url = String::new();
+    let mut pairs = vec![json!(["/", "/"])];
+    for component in vpath.components() {
+        if let std::path::Component::Normal(name) = component {
+            let name = name.to_string_lossy();
+            url.push('/');
+            url.push_str(&encode_path(&name));
+            pairs.push(json!([name, url]));
+        }
+    }
+    Value::Array(pairs)
+}
+
+/// Key by which the entries of a directory listing are ordered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    /// Order by file name (byte order)
+    Name,
+    /// Order by last modified time
+    Mtime,
+    /// Order by creation time
+    Ctime,
+    /// Order by file size
+    Size,
+    /// Shuffle randomly (direction is ignored), the same way each
+    /// time for the same seed, if given
+    Random(Option<u64>),
+}
+
+/// How to order a directory listing
+///
+/// Parsed from the `sort` query parameter, which takes the form
+/// `key[:direction]`, where `key` is one of `name`, `mtime`, `ctime`,
+/// `size` or `random`, and `direction` is one of `asc` (default) or
+/// `desc`. In place of a direction, `random` may take a seed (an
+/// unsigned integer), which makes the shuffle repeatable.
+///
+/// For example: `?sort=mtime:desc`, or `?sort=random:42`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ListSort {
+    /// What to sort by
+    key: SortKey,
+    /// Whether to reverse the order
+    desc: bool,
+}
+
+impl FromStr for ListSort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, dir) = s.split_once(':').unwrap_or((s, "asc"));
+        let key = match key {
+            "name" => SortKey::Name,
+            "mtime" => SortKey::Mtime,
+            "ctime" => SortKey::Ctime,
+            "size" => SortKey::Size,
+            "random" => match dir.parse() {
+                Ok(seed) => {
+                    return Ok(Self {
+                        key: SortKey::Random(Some(seed)),
+                        desc: false,
+                    })
+                }
+                Err(_) => SortKey::Random(None),
+            },
+            _ => return Err(anyhow!("unknown sort key: {key:?}")),
+        };
+        let desc = match dir {
+            "asc" => false,
+            "desc" => true,
+            _ => return Err(anyhow!("unknown sort direction: {dir:?}")),
+        };
+        Ok(Self { key, desc })
+    }
+}
+
+impl ListSort {
+    /// Sort the metadata in place
+    fn apply(&self, mds: &mut [FileMetadata]) {
+        let bykey = |a: &FileMetadata, b: &FileMetadata| match self.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Mtime => a.last_modified.cmp(&b.last_modified),
+            SortKey::Ctime => a.created.cmp(&b.created),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Random(_) => {
+                unreachable!("random is handled separately")
+            }
+        };
+        match self.key {
+            SortKey::Random(None) => {
+                mds.shuffle(&mut rand::thread_rng());
+                return;
+            }
+            SortKey::Random(Some(seed)) => {
+                // Start from the same order (by name) whatever order
+                // the directory was read in, so that the seed alone
+                // decides the result.
+                mds.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+                mds.shuffle(&mut StdRng::seed_from_u64(seed));
+                return;
+            }
+            _ => {}
+        }
+        // Break ties by the name so that the order is deterministic.
+        mds.sort_by(|a, b| {
+            let o = bykey(a, b).then_with(|| a.file_name.cmp(&b.file_name));
+            if self.desc {
+                o.reverse()
+            } else {
+                o
+            }
+        });
+    }
+}
+
+/// Query parameters accepted by the list API
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    /// See [`ListSort`]. If absent, by name (ascending).
+    sort: Option<String>,
+    /// See [`ListVersion`]
+    apiver: Option<String>,
+}
+
+/// Make a weak entity tag from a file's size and last modified time
+/// (to the nanosecond)
+fn weak_etag(md: &FileMetadata) -> Option<String> {
+    let lmo = md.last_modified?;
+    let size = md.size.unwrap_or_default();
+    Some(format!("W/\"{size:x}-{:x}\"", lmo.sgnunixnsec()))
+}
+
+/// Strong entity tags made from the content of files (BLAKE3),
+/// remembered by real path along with the size and last modified time
+/// that they were made for
+#[derive(Debug, Clone, Default)]
+struct ContentHashes {
+    known: Arc<Mutex<HashMap<PathBuf, (FileMetadata, String)>>>,
+    /// Hash the file every time, not trusting the size and last
+    /// modified time to tell whether it has changed
+    rehash: bool,
+}
+
+impl ContentHashes {
+    /// Forget everything once this many files are remembered
+    const CAPACITY: usize = 10_000;
+
+    /// Entity tags made by hashing the file every time (see
+    /// [`ContentHashes::etag`])
+    fn rehashing() -> Self {
+        Self {
+            rehash: true,
+            ..Default::default()
+        }
+    }
+
+    /// Make a strong entity tag from the content of the file,
+    /// hashing it only if it's not remembered or it has changed
+    /// since (judging by the size and last modified time), or always
+    /// if rehashing.
+    async fn etag(
+        &self,
+        real_path: &RealPath,
+        md: &FileMetadata,
+    ) -> Option<String> {
+        let known = self.known.lock().unwrap().get(real_path).cloned();
+        if let Some((known, etag)) = known.filter(|_| !self.rehash) {
+            if known.size == md.size && known.last_modified == md.last_modified
+            {
+                return Some(etag);
+            }
+        }
+
+        let path = real_path.to_owned();
+        let hash = tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(path)?;
+            let mut hasher = blake3::Hasher::new();
+            std::io::copy(&mut file, &mut hasher)?;
+            Ok::<_, std::io::Error>(hasher.finalize())
+        })
+        .await;
+        let hash = match hash {
+            Ok(Ok(hash)) => hash,
+            e => {
+                tracing::warn!("hash {real_path:?}: {e:?}");
+                return None;
+            }
+        };
+        let etag = format!("\"{}\"", hash.to_hex());
+        if self.rehash {
+            return Some(etag);
+        }
+
+        let mut hashes = self.known.lock().unwrap();
+        if hashes.len() >= Self::CAPACITY {
+            hashes.clear();
+        }
+        hashes.insert(real_path.to_owned(), (md.clone(), etag.clone()));
+        Some(etag)
+    }
+}
+
+/// Allow ContentHashes to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for ContentHashes {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts
+            .extensions
+            .get::<ContentHashes>()
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("content hashes not set"))
+            })
+    }
+}
+
+/// Set the ContentHashes in the request
+#[instrument(skip(req, next))]
+async fn mw_set_content_hashes<B>(
+    State(hashes): State<ContentHashes>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(hashes);
+    next.run(req).await
+}
+
+/// Whether an If-None-Match header value matches the entity tag,
+/// using the weak comparison (as required for If-None-Match)
+fn if_none_match(header: &str, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+    header
+        .split(',')
+        .any(|t| t.trim() == "*" || opaque(t) == etag)
+}
+
+/// Whether an If-Range header value matches the current
+/// representation: an entity tag, by the strong comparison (so weak
+/// ones never match), or a date, exactly equal to the last modified
+/// time (to the second)
+fn if_range(header: &str, etag: &str, lmo: Option<DateTime>) -> bool {
+    let header = header.trim();
+    if header.starts_with('"') || header.starts_with("W/") {
+        return !etag.starts_with("W/") && header == etag;
+    }
+    match (DateTime::from_http(header), lmo) {
+        (Ok(date), Some(lmo)) => lmo.seccmp(&date).is_eq(),
+        _ => false,
+    }
+}
+
+/// HTTP caching for regular files by comparing entity tags
+/// (If-None-Match), made from the size and the last modified time.
+///
+/// This works even when a file changes twice in the same second,
+/// which [`mw_cache_http_reval_lmo`] can't tell apart. It composes
+/// with it when layered outside of it: if the client sends
+/// If-None-Match, then If-Modified-Since is ignored (as RFC 9110
+/// says), so only the entity tag decides.
+///
+/// If [`ContentHashes`] are set, the entity tags are strong ones made
+/// from the content of the files (local files only) instead.
+///
+/// Also, a Range request with If-Range gets the whole file instead
+/// of a part of it if the file has changed since (see [`if_range`]),
+/// so that resumed downloads don't mix old and new bytes.
+#[instrument(skip(req, next), err)]
+async fn mw_cache_http_reval_etag(
+    backend: Option<Backend>,
+    hashes: Option<ContentHashes>,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    mut req: http::Request<Body>,
+    next: Next<Body>,
+) -> ApiResult<Response> {
+    // Make the entity tag, if this is a regular file
+    let backend = backend_or_local(backend);
+    let md = backend
+        .read_metadata(&chroot, &vpath)
+        .await
+        .ok()
+        .filter(|md| md.file_type == FileType::RegularFile);
+    let etag = match (&md, hashes) {
+        (Some(md), Some(hashes)) => {
+            hashes.etag(&chroot.join(&*vpath), md).await
+        }
+        (Some(md), None) => weak_etag(md),
+        _ => None,
+    };
+    let Some(etag) = etag else {
+        tracing::trace!("no entity tag for virtual path {vpath:?}");
+        return Ok(next.run(req).await);
+    };
+    let etag = HeaderValue::from_str(&etag)
+        .context("convert entity tag to header value")
+        .map_err(ApiError::with_status(500))?;
+
+    // Compare with what the client has
+    let inm = req.headers().get(header::IF_NONE_MATCH).cloned();
+    if let Some(inm) = inm {
+        let inm = inm
+            .to_str()
+            .context("convert if-none-match to &str")
+            .map_err(ApiError::with_status(400))?;
+        if if_none_match(inm, etag.to_str().unwrap_or_default()) {
+            tracing::trace!("fresh");
+            let mut res = StatusCode::NOT_MODIFIED.into_response();
+            res.headers_mut().insert(header::ETAG, etag);
+            return Ok(res);
+        }
+        tracing::trace!("stale");
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+
+    // Send only a part if the client has the rest of the same file.
+    if req.headers().contains_key(header::RANGE) {
+        if let Some(ir) = req.headers().get(header::IF_RANGE) {
+            let lmo = md.as_ref().and_then(|md| md.last_modified);
+            let ir = ir.to_str().unwrap_or_default();
+            if !if_range(ir, etag.to_str().unwrap_or_default(), lmo) {
+                tracing::trace!("if-range does not match, send all");
+                req.headers_mut().remove(header::RANGE);
+            }
+        }
+    }
+
+    let mut res = next.run(req).await;
+    if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+        res.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(res)
+}
+
+/// Leave out the hits of a search that ignore files hide (see
+/// [`IGNORE_FILE`]), finding out what is hidden in each directory
+/// only once
+async fn drop_ignored(
+    visibility: &Visibility,
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    hits: Vec<SearchHit>,
+) -> Vec<SearchHit> {
+    let mut hiders: HashMap<PathBuf, Hider> = HashMap::new();
+    let mut kept = vec![];
+    for (hpath, md) in hits {
+        let dir = hpath.parent().unwrap_or(&hpath).to_owned();
+        if !hiders.contains_key(&dir) {
+            let hider = visibility.in_dir(backend, chroot, &dir).await;
+            hiders.insert(dir.clone(), hider);
+        }
+        if !hiders[&dir].hides(&hpath, md.file_type == FileType::Directory) {
+            kept.push((hpath, md));
+        }
+    }
+    kept
+}
+
+/// Gather the contents of a directory, recursively, as
+/// [`ArchiveEntry`]s, descending at most `depth` levels and taking at
+/// most `limit` objects.
+///
+/// The same rules as [`search_directory`] apply, so nothing outside
+/// the chroot, nothing failing [`bad_path1`], and nothing hidden (see
+/// [`Visibility`]) is included.
+async fn gather_archive_entries(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    visibility: &Visibility,
+    depth: usize,
+    limit: usize,
+) -> ApiResult<Vec<ArchiveEntry>> {
+    let visible = |hpath: &VirtualPath| !visibility.policy.hides(hpath);
+    let (hits, truncated) = search_directory(
+        backend,
+        chroot,
+        vpath,
+        |_| true,
+        visible,
+        depth,
+        limit,
+    )
+    .await
+    .map_err(ApiError::with_status(404))?;
+    let hits = drop_ignored(visibility, backend, chroot, hits).await;
+    if truncated {
+        tracing::warn!("archive of {vpath:?} truncated at {limit} objects");
+    }
+    let entries = hits
+        .into_iter()
+        .filter_map(|(hpath, md)| {
+            let name = hpath
+                .strip_prefix(vpath)
+                .ok()?
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()?
+                .join("/");
+            Some(ArchiveEntry {
+                virt_path: hpath,
+                name,
+                is_dir: md.file_type == FileType::Directory,
+                size: md.size,
+                last_modified: md.last_modified,
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Characters to percent-encode in an RFC 5987 extended header
+/// parameter value (all but `attr-char`)
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Make a Content-Disposition header value for downloading as the
+/// given file name, which may be any UTF-8
+fn attachment_utf8(filename: &str) -> HeaderValue {
+    let filename = percent_encode(filename.as_bytes(), ATTR_CHAR);
+    HeaderValue::from_str(&format!("attachment; filename*=UTF-8''{filename}"))
+        .expect("expect a percent-encoded name to be a valid header value")
+}
+
+/// Query parameters accepted by the download API
+#[derive(Debug, Deserialize)]
+struct DownloadQuery {
+    /// Archive format for directories: `zip` (default) or `tar.gz`
+    format: Option<String>,
+    /// Suggest this file name to the client (see
+    /// [`mw_download_filename`])
+    filename: Option<String>,
+}
+
+/// If the `filename` query parameter is given, suggest it as the name
+/// to download as (with Content-Disposition), in place of the name in
+/// the path.
+///
+/// The name must be a single, valid path component (see
+/// [`bad_path1`]), or else the request gets 400 Bad Request.
+#[instrument(skip(req, next), err)]
+async fn mw_download_filename(
+    Query(query): Query<DownloadQuery>,
+    req: http::Request<Body>,
+    next: Next<Body>,
+) -> ApiResult<Response> {
+    let Some(filename) = query.filename else {
+        return Ok(next.run(req).await);
+    };
+    let mut components = std::path::Path::new(&filename).components();
+    let single = matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    );
+    if !single || bad_path1(&filename) {
+        return Err(ApiError::with_status(400)(anyhow!(
+            "bad file name: {filename:?}"
+        )));
+    }
+
+    let mut res = next.run(req).await;
+    if res.status().is_success() {
+        res.headers_mut()
+            .insert(header::CONTENT_DISPOSITION, attachment_utf8(&filename));
+    }
+    Ok(res)
+}
+
+/// Rules for downloading, set once at startup
+#[derive(Debug, Clone)]
+pub struct DownloadPolicy {
+    /// If set, a directory that contains a regular file of this name
+    /// (a plain file name, such as `index.html`) is served as that
+    /// file instead of as an archive, unless an archive `format` is
+    /// asked for explicitly. Off by default.
+    pub index_file: Option<String>,
+    /// Make strong entity tags by hashing the content of files,
+    /// instead of weak ones from the size and last modified time, so
+    /// that files touched but unchanged are still fresh in caches.
+    /// Each file is hashed once per change. Off by default.
+    pub content_etag: bool,
+    /// Serve files with these extensions (lowercase, without the dot,
+    /// such as `log` or `heic`) as these MIME types (such as
+    /// `text/plain; charset=utf-8` or `image/heic`), in place of the
+    /// type guessed from the extension. Empty by default.
+    pub content_types: HashMap<String, String>,
+    /// Read and send files (and archives) this many bytes at a time.
+    /// Larger chunks suit fast storage and large files; smaller ones
+    /// use less memory per download. 64 KiB by default.
+    pub chunk_size: usize,
+}
+
+impl Default for DownloadPolicy {
+    fn default() -> Self {
+        Self {
+            index_file: None,
+            content_etag: false,
+            content_types: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+/// The size of the chunks to send archives in (as an HTTP extension;
+/// see [`DownloadPolicy::chunk_size`])
+#[derive(Debug, Clone, Copy)]
+struct ChunkSize(usize);
+
+/// Allow ChunkSize to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for ChunkSize {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts.extensions.get::<ChunkSize>().copied().ok_or_else(|| {
+            ApiError::with_status(500)(anyhow!("chunk size not set"))
+        })
+    }
+}
+
+/// Set the ChunkSize in the request
+#[instrument(skip(req, next))]
+async fn mw_set_chunk_size<B>(
+    State(chunk_size): State<usize>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(ChunkSize(chunk_size));
+    next.run(req).await
+}
+
+/// Override the Content-Type of files by their extension (see
+/// [`DownloadPolicy::content_types`]; the extension is matched
+/// ignoring case)
+#[instrument(skip(req, next))]
+async fn mw_content_types(
+    State(types): State<Arc<HashMap<String, HeaderValue>>>,
+    req: http::Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let content_type = req
+        .extensions()
+        .get::<VPath>()
+        .and_then(|VPath(vpath)| vpath.extension())
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| types.get(&ext.to_ascii_lowercase()))
+        .cloned();
+    let mut res = next.run(req).await;
+    if let Some(content_type) = content_type {
+        if res.status().is_success() {
+            res.headers_mut().insert(header::CONTENT_TYPE, content_type);
+        }
+    }
+    res
+}
+
+/// Serve the index file of a directory in place of the directory, if
+/// it exists, by rewriting the request.
+#[instrument(skip(req, next))]
+async fn mw_serve_index(
+    State(index_file): State<Arc<str>>,
+    mut req: http::Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // An explicit archive format wins.
+    let query = Query::<DownloadQuery>::try_from_uri(req.uri());
+    if query.map_or(true, |q| q.format.is_some()) {
+        return next.run(req).await;
+    }
+    let (Some(Chroot(chroot)), Some(VPath(vpath))) = (
+        req.extensions().get::<Chroot>().cloned(),
+        req.extensions().get::<VPath>().cloned(),
+    ) else {
+        return next.run(req).await;
+    };
+
+    // Is there an index file?
+    let vpath = vpath.join(&*index_file);
+    let is_file = tokio::fs::metadata(chroot.join(&vpath))
+        .await
+        .map(|md| md.is_file())
+        .unwrap_or(false);
+    if !is_file {
+        return next.run(req).await;
+    }
+
+    // Point the request at the index file
+    let path =
+        format!("{}/{index_file}", req.uri().path().trim_end_matches('/'));
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match req.uri().query() {
+        Some(q) => format!("{path}?{q}").parse().ok(),
+        None => path.parse().ok(),
+    };
+    let Ok(uri) = http::Uri::from_parts(parts) else {
+        return next.run(req).await;
+    };
+    tracing::trace!("serve index {uri}");
+    *req.uri_mut() = uri;
+    req.extensions_mut().insert(VPath(Arc::new(vpath)));
+    next.run(req).await
+}
+
+/// Download a directory as an archive
+///
+/// If the virtual path refers to a directory, respond with an archive
+/// of its contents (descending at most (DEPTH) levels and taking at
+/// most (LIMIT) objects), built on the fly. Otherwise, pass the
+/// request on.
+///
+/// The format is ZIP unless the `format` query parameter says
+/// `tar.gz`.
+#[allow(clippy::too_many_arguments)] // (extractors)
+#[instrument(skip(req, next), err)]
+async fn mw_archive_directories<const DEPTH: usize, const LIMIT: usize>(
+    backend: Option<Backend>,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    visibility: Option<Visibility>,
+    ChunkSize(chunk_size): ChunkSize,
+    Query(query): Query<DownloadQuery>,
+    req: http::Request<Body>,
+    next: Next<Body>,
+) -> ApiResult<Response> {
+    let backend = backend_or_local(backend);
+    let is_dir = backend
+        .read_metadata(&chroot, &vpath)
+        .await
+        .map(|md| md.file_type == FileType::Directory)
+        .unwrap_or(false);
+    if !is_dir {
+        return Ok(next.run(req).await);
+    }
+
+    let format = archive_format(query.format.as_deref())?;
+    let visibility = visibility_or_default(visibility);
+    let entries = gather_archive_entries(
+        &*backend,
+        &chroot,
+        &vpath,
+        &visibility,
+        DEPTH,
+        LIMIT,
+    )
+    .await?;
+    let dirname = vpath
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("root");
+    let filename = format!("{dirname}.{}", format.extension());
+    let body = StreamBody::new(ReaderStream::with_capacity(
+        archive_stream(backend, chroot, format, entries),
+        chunk_size,
+    ));
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            ),
+            (header::CONTENT_DISPOSITION, attachment_utf8(&filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Parse the `format` query parameter of the download APIs: `zip`
+/// (default) or `tar.gz`
+fn archive_format(format: Option<&str>) -> ApiResult<ArchiveFormat> {
+    match format {
+        None | Some("zip") => Ok(ArchiveFormat::Zip),
+        Some("tar.gz") => Ok(ArchiveFormat::TarGz),
+        Some(f) => Err(ApiError::with_status(400)(anyhow!(
+            "unknown archive format: {f:?}"
+        ))),
+    }
+}
+
+/// Download the files (not directories) at a JSON array of virtual
+/// paths, at most (MAXFILES) of them, as one archive, built on the
+/// fly
+///
+/// Each path is checked as the guard would check it. If any path is
+/// bad or hidden, or isn't a regular file, the whole request fails
+/// (with that path's status), before anything is sent. Inside the
+/// archive, the files are named by their paths from the root; a path
+/// given more than once is archived once.
+///
+/// The format is ZIP unless the `format` query parameter says
+/// `tar.gz`, and the archive is named `files.zip` (or `files.tar.gz`)
+/// unless the `filename` query parameter says otherwise.
+#[instrument(err, skip(paths))]
+async fn api_archive<const MAXFILES: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    ChunkSize(chunk_size): ChunkSize,
+    Query(query): Query<DownloadQuery>,
+    axum::Json(paths): axum::Json<Vec<String>>,
+) -> ApiResult<Response> {
+    if paths.is_empty() {
+        return Err(ApiError::with_status(400)(anyhow!("no paths")));
+    }
+    if paths.len() > MAXFILES {
+        return Err(ApiError::with_status(413)(anyhow!(
+            "too many paths: {} > {MAXFILES}",
+            paths.len()
+        )));
+    }
+    let format = archive_format(query.format.as_deref())?;
+
+    // Check every path before archiving any
+    let visibility = visibility_or_default(visibility);
+    let mut seen = HashSet::new();
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let vpath =
+            check_virt_path(&*backend, &visibility, &chroot, path.as_ref())
+                .await?;
+        let md = backend
+            .read_metadata(&chroot, &vpath)
+            .await
+            .map_err(ApiError::with_status(404))?;
+        if md.file_type != FileType::RegularFile {
+            return Err(ApiError::with_status(400)(anyhow!(
+                "not a file: {vpath:?}"
+            )));
+        }
+        let name = vpath
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                ApiError::with_status(400)(anyhow!("non-UTF-8: {vpath:?}"))
+            })?
+            .join("/");
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        entries.push(ArchiveEntry {
+            virt_path: vpath,
+            name,
+            is_dir: false,
+            size: md.size,
+            last_modified: md.last_modified,
+        });
+    }
+
+    let filename = query
+        .filename
+        .unwrap_or_else(|| format!("files.{}", format.extension()));
+    let body = StreamBody::new(ReaderStream::with_capacity(
+        archive_stream(backend, chroot, format, entries),
+        chunk_size,
+    ));
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            ),
+            (header::CONTENT_DISPOSITION, attachment_utf8(&filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Body of a request to the move API (see [`api_mv`])
+#[derive(Debug, Deserialize)]
+struct MoveRequest {
+    /// Virtual path of the object to move
+    from: String,
+    /// Virtual path to move it to, in an existing directory
+    to: String,
+    /// Replace a file already at `to` (never a directory). Off by
+    /// default.
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Move (or rename) a file or directory, as asked for by a JSON
+/// object like `{"from": "/a.txt", "to": "/b/c.txt"}`, and respond
+/// with the virtual paths moved from and to (in the same form)
+///
+/// Both paths are checked as the guard would check them, and so are
+/// confined to the chroot: `from` must exist; `to` must be in an
+/// existing directory, and must not be hidden. An object already at
+/// `to` is left alone (409 Conflict), unless `overwrite` is set and
+/// it is a file.
+#[instrument(err)]
+async fn api_mv(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    axum::Json(req): axum::Json<MoveRequest>,
+) -> ApiResult<Response> {
+    let visibility = visibility_or_default(visibility);
+
+    // From
+    let from =
+        check_virt_path(&*backend, &visibility, &chroot, req.from.as_ref())
+            .await?;
+    if from.as_os_str().is_empty() {
+        return Err(ApiError::with_status(400)(anyhow!("can't move root")));
+    }
+    let is_dir = backend
+        .read_metadata(&chroot, &from)
+        .await
+        .map_err(ApiError::with_status(404))?
+        .file_type
+        == FileType::Directory;
+
+    // To (which need not exist, but its directory must)
+    let to = check_new_virt_path(
+        &*backend,
+        &visibility,
+        &chroot,
+        req.to.as_ref(),
+        is_dir,
+    )
+    .await?;
+
+    // Don't replace what isn't meant to be replaced. (The check and
+    // the move aren't atomic, but only a writer outside of this
+    // server could slip in between.)
+    let (real_from, real_to) = (chroot.join(&from), chroot.join(&to));
+    if let Ok(md) = tokio::fs::symlink_metadata(&real_to).await {
+        if md.is_dir() || !req.overwrite {
+            return Err(ApiError::with_status(409)(anyhow!(
+                "already exists: {to:?}"
+            )));
+        }
+    }
+    tokio::fs::rename(&real_from, &real_to).await.map_err(|e| {
+        let status = match e.kind() {
+            std::io::ErrorKind::NotFound => 404,
+            std::io::ErrorKind::PermissionDenied => 403,
+            _ => 409,
+        };
+        ApiError::with_status(status)(
+            Error::from(e).context(format!("rename {from:?} to {to:?}")),
+        )
+    })?;
+    tracing::info!("moved {from:?} to {to:?}");
+
+    let (from, to) = (Path::new("/").join(from), Path::new("/").join(to));
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        json!({ "from": from, "to": to }).to_string(),
+    )
+        .into_response())
+}
+
+/// Rules for uploading, set once at startup
+#[derive(Debug, Clone)]
+pub struct UploadPolicy {
+    /// Refuse (with 413 Payload Too Large) uploads of more than this
+    /// many bytes, counted as they arrive. 100 MiB by default.
+    pub max_size: u64,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// The [`UploadPolicy`] (as an HTTP extension)
+#[derive(Debug, Clone)]
+struct UploadSettings(Arc<UploadPolicy>);
+
+/// Allow UploadSettings to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for UploadSettings {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts
+            .extensions
+            .get::<UploadSettings>()
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("upload policy not set"))
+            })
+    }
+}
+
+/// Set the UploadSettings in the request
+#[instrument(skip(req, next))]
+async fn mw_set_upload_settings<B>(
+    State(policy): State<Arc<UploadPolicy>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(UploadSettings(policy));
+    next.run(req).await
+}
+
+/// Query parameters accepted by the upload API
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    /// `1` (or `true`) to replace a file already at the path
+    overwrite: Option<String>,
+}
+
+/// Store the request body as the file at the virtual path, and
+/// respond with its metadata, like the stat API does (see
+/// [`api_stat`])
+///
+/// The path is checked by [`check_new_virt_path`]. An object already
+/// at the path is left alone (409 Conflict), unless it is a file and
+/// `?overwrite=1` is given. The body is written, as it arrives, to a
+/// temporary file beside the target, which is then moved into place
+/// at once, so that the file is never seen half-written. Bodies
+/// larger than the [`UploadPolicy`] allows are refused (413 Payload
+/// Too Large), as soon as that is known.
+#[instrument(err, skip(req))]
+async fn api_upload(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    UploadSettings(policy): UploadSettings,
+    axum::extract::Path(vpath): axum::extract::Path<PathBuf>,
+    Query(query): Query<UploadQuery>,
+    req: http::Request<Body>,
+) -> ApiResult<Response> {
+    // Check the path, what's there, and the declared length, if any
+    let visibility = visibility_or_default(visibility);
+    let vpath =
+        check_new_virt_path(&*backend, &visibility, &chroot, &vpath, false)
+            .await?;
+    let overwrite = matches!(query.overwrite.as_deref(), Some("1" | "true"));
+    let real_path = chroot.join(&vpath);
+    if let Ok(md) = tokio::fs::symlink_metadata(&real_path).await {
+        if md.is_dir() || !overwrite {
+            return Err(ApiError::with_status(409)(anyhow!(
+                "already exists: {vpath:?}"
+            )));
+        }
+    }
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if length.is_some_and(|length| length > policy.max_size) {
+        return Err(ApiError::with_status(413)(anyhow!(
+            "upload too large: {length:?}"
+        )));
+    }
+
+    // Write to a temporary file, which is removed once done with, even
+    // if the request is dropped halfway (such as by the client)
+    let temp = UploadTemp(real_path.with_file_name(format!(
+        ".gagaga-upload-{:016x}",
+        rand::random::<u64>()
+    )));
+    write_upload(&temp.0, req.into_body(), policy.max_size).await?;
+
+    // Move into place: replace by renaming, or else link (which, unlike
+    // renaming, fails if the target appeared meanwhile)
+    let placed = if overwrite {
+        tokio::fs::rename(&temp.0, &real_path).await
+    } else {
+        tokio::fs::hard_link(&temp.0, &real_path).await
+    };
+    drop(temp);
+    placed.map_err(|e| {
+        let status = match e.kind() {
+            std::io::ErrorKind::AlreadyExists => 409,
+            std::io::ErrorKind::PermissionDenied => 403,
+            _ => 500,
+        };
+        ApiError::with_status(status)(
+            Error::from(e).context(format!("place upload at {vpath:?}")),
+        )
+    })?;
+    tracing::info!("uploaded {vpath:?}");
+
+    created(&*backend, &chroot, &vpath).await
+}
+
+/// The temporary file an upload is written to (see [`api_upload`]),
+/// removed when dropped, if it's still there
+#[derive(Debug)]
+struct UploadTemp(PathBuf);
+
+impl Drop for UploadTemp {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("remove upload {:?}: {e}", self.0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Respond (with 201 Created) with the metadata of an object just
+/// made, in the form of the stat API (see [`api_stat`])
+async fn created(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+) -> ApiResult<Response> {
+    let now_sgnunixsec = DateTime::now().sgnunixsec();
+    let md = backend
+        .read_link_metadata(chroot, vpath)
+        .await
+        .map_err(ApiError::with_status(500))?;
+    let value = json!({
+        "version": "040",
+        "now": now_sgnunixsec,
+        "entry": serfmeta(&md, now_sgnunixsec),
+    });
+    Ok((
+        StatusCode::CREATED,
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        value.to_string(),
+    )
+        .into_response())
+}
+
+/// Query parameters accepted by the mkdir API
+#[derive(Debug, Deserialize)]
+struct MkdirQuery {
+    /// `1` (or `true`) to make any missing parent directories, too
+    parents: Option<String>,
+}
+
+/// Make a directory at the virtual path, and respond with its
+/// metadata, like the upload API does (see [`api_upload`])
+///
+/// The path is checked by [`check_new_virt_path`], so its directory
+/// must exist, unless `?parents=1` is given, in which case the
+/// deepest directory that exists is checked instead (as
+/// [`check_virt_path`] checks any path), and none of the directories
+/// to make may be hidden. Anything already at the path is a 409
+/// Conflict.
+#[instrument(err)]
+async fn api_mkdir(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    axum::extract::Path(vpath): axum::extract::Path<PathBuf>,
+    Query(query): Query<MkdirQuery>,
+) -> ApiResult<Response> {
+    let visibility = visibility_or_default(visibility);
+    let parents = matches!(query.parents.as_deref(), Some("1" | "true"));
+    let vpath = if parents {
+        if bad_path1(&vpath) {
+            return Err(ApiError::with_status(400)(anyhow!(
+                "bad new vpath: {vpath:?}"
+            )));
+        }
+        let vpath = vpath.strip_prefix("/").unwrap_or(&vpath).to_owned();
+
+        // Find the deepest directory that exists (the chroot, at
+        // least), and check it, and then what's to be made below it
+        let mut missing = vec![];
+        let mut existing = vpath.as_path();
+        while tokio::fs::symlink_metadata(chroot.join(existing))
+            .await
+            .is_err()
+        {
+            missing.push(existing);
+            existing = existing.parent().unwrap_or(VirtualPath::new(""));
+        }
+        if missing.is_empty() {
+            return Err(ApiError::with_status(409)(anyhow!(
+                "already exists: {vpath:?}"
+            )));
+        }
+        check_virt_path(&*backend, &visibility, &chroot, existing).await?;
+        for missing in missing {
+            if visibility.hides(&*backend, &chroot, missing, true).await {
+                return Err(ApiError::with_status(403)(anyhow!(
+                    "hidden new vpath: {missing:?}"
+                )));
+            }
+        }
+        vpath
+    } else {
+        check_new_virt_path(&*backend, &visibility, &chroot, &vpath, true)
+            .await?
+    };
+
+    let real_path = chroot.join(&vpath);
+    let made = if parents {
+        tokio::fs::create_dir_all(&real_path).await
+    } else {
+        tokio::fs::create_dir(&real_path).await
+    };
+    made.map_err(|e| {
+        let status = match e.kind() {
+            std::io::ErrorKind::AlreadyExists
+            | std::io::ErrorKind::NotADirectory => 409,
+            std::io::ErrorKind::NotFound => 404,
+            std::io::ErrorKind::PermissionDenied => 403,
+            _ => 500,
+        };
+        ApiError::with_status(status)(
+            Error::from(e).context(format!("make directory {vpath:?}")),
+        )
+    })?;
+    tracing::info!("made directory {vpath:?}");
+
+    created(&*backend, &chroot, &vpath).await
+}
+
+/// Write an upload to a new file at the real path, refusing (with
+/// 413 Payload Too Large) to write more than `max_size` bytes
+async fn write_upload(
+    real_path: &RealPath,
+    mut body: Body,
+    max_size: u64,
+) -> ApiResult<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(real_path)
+        .await
+        .context("create upload file")
+        .map_err(ApiError::with_status(500))?;
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk
+            .context("read upload")
+            .map_err(ApiError::with_status(400))?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(ApiError::with_status(413)(anyhow!(
+                "upload too large: > {max_size}"
+            )));
+        }
+        file.write_all(&chunk)
+            .await
+            .context("write upload")
+            .map_err(ApiError::with_status(500))?;
+    }
+    file.sync_all()
+        .await
+        .context("sync upload")
+        .map_err(ApiError::with_status(500))?;
+    Ok(())
+}
+
+/// Query parameters accepted by the delete API
+#[derive(Debug, Deserialize)]
+struct DeleteQuery {
+    /// `1` (or `true`) to remove a directory with everything in it
+    recursive: Option<String>,
+}
+
+/// Delete the file or (empty) directory at the virtual path, or, with
+/// `?recursive=1`, the directory and everything in it, and respond
+/// with the number of objects removed, like `{"removed": 3}`
+///
+/// The directory of the path is checked as the guard would check it,
+/// but the object itself is not followed, so that a link (even a
+/// broken one) is removed as itself. Links are never followed while
+/// recursing either (see [`crate::fs::remove`]). The root can't be
+/// deleted, and hidden objects are not found. A directory that isn't
+/// empty is left alone (409 Conflict) unless `recursive` is given.
+#[instrument(err)]
+async fn api_delete(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    visibility: Option<Visibility>,
+    axum::extract::Path(vpath): axum::extract::Path<PathBuf>,
+    Query(query): Query<DeleteQuery>,
+) -> ApiResult<Response> {
+    let visibility = visibility_or_default(visibility);
+    if bad_path1(&vpath) {
+        return Err(ApiError::with_status(400)(anyhow!(
+            "bad vpath: {vpath:?}"
+        )));
+    }
+    let vpath = vpath.strip_prefix("/").unwrap_or(&vpath);
+    let (Some(parent), Some(name)) = (vpath.parent(), vpath.file_name()) else {
+        return Err(ApiError::with_status(400)(anyhow!("can't delete root")));
+    };
+    let parent =
+        check_virt_path(&*backend, &visibility, &chroot, parent).await?;
+    let vpath = parent.join(name);
+    let md = backend
+        .read_link_metadata(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let is_dir = md.file_type == FileType::Directory;
+    if visibility.hides(&*backend, &chroot, &vpath, is_dir).await {
+        return Err(ApiError::with_status(404)(anyhow!(
+            "hidden vpath: {vpath:?}"
+        )));
+    }
+
+    let recursive = matches!(query.recursive.as_deref(), Some("1" | "true"));
+    let removed = crate::fs::remove(&*chroot, &*vpath, recursive)
+        .await
+        .map_err(|e| {
+            let kind = e.downcast_ref::<std::io::Error>().map(|e| e.kind());
+            let status = match kind {
+                Some(std::io::ErrorKind::NotFound) => 404,
+                Some(std::io::ErrorKind::PermissionDenied) => 403,
+                Some(std::io::ErrorKind::DirectoryNotEmpty) => 409,
+                _ => 500,
+            };
+            ApiError::with_status(status)(e)
+        })?;
+    tracing::info!("deleted {vpath:?} ({removed} objects)");
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        json!({ "removed": removed }).to_string(),
+    )
+        .into_response())
+}
+
+/// Decide how to list an entry of a directory: as itself, as what it
+/// links to, or not at all (`None`).
+///
+/// Links are followed unless the [`ListPolicy`] says otherwise, and
+/// reported as their targets. Broken links are kept (as links), but
+/// not those that lead out of the chroot. Anything that's neither a
+/// file nor a directory, even after following, is left out.
+async fn resolve_entry(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    md: FileMetadata,
+    policy: &ListPolicy,
+) -> Option<FileMetadata> {
+    match md.file_type {
+        FileType::RegularFile | FileType::Directory => return Some(md),
+        FileType::Link if !policy.follow_symlinks => return Some(md),
+        _ => {}
+    }
+
+    // Follow and then categorize. But, use the ORIGINAL metadata.
+    let vpathf = vpath.join(&md.file_name);
+    let Ok(target) = follow_get_md(backend, chroot, &vpathf).await else {
+        let broken = broken_link(backend, chroot, &vpathf).await;
+        if let Some(why) = broken {
+            tracing::debug!("keep broken link {vpathf:?} ({why})");
+        }
+        return broken.and(Some(md));
+    };
+    match target.file_type {
+        FileType::RegularFile | FileType::Directory => Some(target),
+        _ => None,
+    }
+}
+
+/// Read a directory for listing, following links according to the
+/// [`ListPolicy`] (see [`resolve_entry`]), and leaving out whatever
+/// the [`VisibilityPolicy`] hides. Returns the directories,
+/// the files (and links), in no particular order, and whether the
+/// listing was cut short.
+async fn read_entries(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    policy: &ListPolicy,
+    visibility: &Visibility,
+) -> ApiResult<(Vec<FileMetadata>, Vec<FileMetadata>, bool)> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+
+    let stream = backend.list_directory(chroot, vpath).await;
+    // Check if it's due to insufficient permissions
+    if let Err(e) = stream {
+        if let Ok(e) = e.downcast::<std::io::Error>() {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                return Err(ApiError::with_status(403)(e));
+            } else {
+                return Err(ApiError::with_status(404)(e));
+            }
+        }
+        return Err(ApiError::with_status(404)(anyhow!(
+            "error building stream"
+        )));
+    }
+    // Follow links (several at once), and then categorize
+    let hider = visibility.in_dir(backend, chroot, vpath).await;
+    let hides = |md: &FileMetadata| {
+        let is_dir = md.file_type == FileType::Directory;
+        hider.hides(&vpath.join(&md.file_name), is_dir)
+    };
+    let stream = stream
+        .unwrap()
+        .filter_map(|md| md.ok())
+        .filter(|md| md.raw_name.is_none() || policy.lossy_names)
+        .filter(|md| !hides(md))
+        .map(|md| resolve_entry(backend, chroot, vpath, md, policy));
+    let mut stream = Box::pin(futures_util::StreamExt::buffer_unordered(
+        stream,
+        policy.concurrency.max(1),
+    ));
+    let mut truncated = false;
+    while let Some(md) = stream.next().await {
+        // (A followed link is named after its target, which may be
+        // hidden.)
+        let Some(md) = md.filter(|md| !hides(md)) else {
+            continue;
+        };
+
+        // Stop at the limit
+        if dirs.len() + files.len() >= policy.max_entries {
+            truncated = true;
+            break;
+        }
+
+        if md.file_type == FileType::Directory {
+            dirs.push(md);
+        } else {
+            files.push(md);
+        }
+    }
+
+    Ok((dirs, files, truncated))
+}
+
+/// Handle listing the directory into a JSON response
+///
+/// The schema of the entries depends on the [`ListVersion`]. An
+/// unsupported version gets 406 Not Acceptable.
+#[instrument(err)]
+async fn api_list(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    Policy(policy): Policy,
+    visibility: Option<Visibility>,
+    Query(query): Query<ListQuery>,
+    headers: http::HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    // Decide on the version and the order before doing any work
+    let version = query
+        .apiver
+        .as_deref()
+        .or_else(|| headers.get("accept-version").and_then(|v| v.to_str().ok()))
+        .map(ListVersion::from_str)
+        .transpose()
+        .map_err(ApiError::with_status(406))?
+        .unwrap_or_default();
+    let sort = query
+        .sort
+        .as_deref()
+        .map(ListSort::from_str)
+        .transpose()
+        .map_err(ApiError::with_status(400))?;
+
+    // Measure the time now and round it down to the second
+    let now_sgnunixsec = DateTime::now().sgnunixsec();
+
+    // Read the directory
+    let (mut dirs, mut files, truncated) = read_entries(
+        &*backend,
+        &chroot,
+        &vpath,
+        &policy,
+        &visibility_or_default(visibility),
+    )
+    .await?;
+
+    // Order (links were followed in no particular order), and then
+    // serialize each entry
+    let sort = sort.unwrap_or(ListSort {
+        key: SortKey::Name,
+        desc: false,
+    });
+    sort.apply(&mut dirs);
+    sort.apply(&mut files);
+    let dirs: Vec<_> = dirs
+        .iter()
+        .map(|md| version.serfmeta(md, now_sgnunixsec))
+        .collect();
+    let mut files: Vec<_> = files
+        .iter()
+        .map(|md| version.serfmeta(md, now_sgnunixsec))
+        .collect();
+    if version >= ListVersion::V046 && policy.follow_symlinks {
+        // Whatever is still a link couldn't be followed.
+        for value in &mut files {
+            if value["type"] != "ln" {
+                continue;
+            }
+            let Some(name) = value["name"].as_str() else {
+                continue;
+            };
+            let vpathl = vpath.join(name);
+            let broken = broken_link(&*backend, &chroot, &vpathl).await;
+            value["broken"] = json!(broken);
+        }
+    }
+
+    // Append necessary metadata and then serialize
+    let mut value = json!({
+        "version": version.as_str(),
+        "now": now_sgnunixsec,
+        "truncated": truncated,
+        "dirs": dirs,
+        "files": files,
+    });
+    if version >= ListVersion::V044 {
+        value["ancestry"] = ancestry(&vpath);
+    }
+    if version >= ListVersion::V045 {
+        // There is nothing above the root (of the chroot).
+        if let Some(parent) = vpath.parent() {
+            let parent = encode_path(&parent.to_string_lossy());
+            value["parent"] = json!(format!("/{parent}"));
+        }
+    }
+    let value = value.to_string();
+
+    // The version may be negotiated by header, so caches must key on it.
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json; charset=utf-8"),
+            (header::VARY, "accept-version"),
+        ],
+        value,
+    ))
+}
+
+/// A row of an autoindex page
+#[derive(Debug)]
+struct IndexRow {
+    /// Where to go (relative to the page)
+    href: String,
+    /// Name of the entry (with a trailing `/` for directories)
+    name: String,
+    /// Last modified time, or `-`
+    last_modified: String,
+    /// Size, in human readable form, or `-`
+    size: String,
+}
+
+/// Autoindex page
+#[derive(Debug, TemplateOnce)]
+#[template(path = "autoindex.html")]
+struct IndexPage {
+    /// Path of the directory, as requested
+    path: String,
+    /// Whether to link to the parent directory
+    has_parent: bool,
+    /// Directories first, and then files, each by name
+    rows: Vec<IndexRow>,
+    /// Whether the listing was cut short
+    truncated: bool,
+}
+
+impl IndexRow {
+    /// Describe a directory entry
+    fn new(md: &FileMetadata) -> Self {
+        let is_dir = md.file_type == FileType::Directory;
+        let slash = if is_dir { "/" } else { "" };
+        Self {
+            href: format!("{}{slash}", encode_path(&md.file_name)),
+            name: format!("{}{slash}", md.file_name),
+            last_modified: md
+                .last_modified
+                .map_or("-".to_string(), |lmo| lmo.rfc3339z()),
+            size: match md.size {
+                Some(n) if !is_dir => format_size_bytes(n, false),
+                _ => "-".to_string(),
+            },
+        }
+    }
+}
+
+/// Handle listing the directory as a plain HTML page (an "autoindex,"
+/// as in Apache or nginx), with relative links, for simple clients
+/// and mirroring tools. No scripts.
+///
+/// Paths without a trailing slash are redirected to one with it, so
+/// that the relative links work.
+#[instrument(err)]
+async fn api_autoindex(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    Policy(policy): Policy,
+    visibility: Option<Visibility>,
+    uri: http::Uri,
+) -> ApiResult<Response> {
+    let path = uri.path();
+    if !path.ends_with('/') {
+        return Ok(Redirect::permanent(&format!("{path}/")).into_response());
+    }
+
+    let (mut dirs, mut files, truncated) = read_entries(
+        &*backend,
+        &chroot,
+        &vpath,
+        &policy,
+        &visibility_or_default(visibility),
+    )
+    .await?;
+    let sort = ListSort {
+        key: SortKey::Name,
+        desc: false,
+    };
+    sort.apply(&mut dirs);
+    sort.apply(&mut files);
+
+    let page = IndexPage {
+        path: percent_decode_str(path).decode_utf8_lossy().into_owned(),
+        has_parent: vpath.parent().is_some(),
+        rows: dirs.iter().chain(&files).map(IndexRow::new).collect(),
+        truncated,
+    }
+    .render_once()
+    .context("render autoindex")
+    .map_err(ApiError::with_status(500))?;
+
+    Ok(([(header::CONTENT_TYPE, "text/html; charset=utf-8")], page)
+        .into_response())
+}
+
+/// Handle listing the directory as a stream of NDJSON lines
+///
+/// Unlike [`api_list`], entries are sent as soon as they are read,
+/// so that the client can render a huge directory incrementally.
+///
+/// The first line is a header object, `{"version": ..., "now": ...}`,
+/// with the same meaning as in the list API. Every following line is
+/// one entry, encoded exactly as in the list API (see [`serfmeta`]).
+/// Links are followed unless the [`ListPolicy`] says otherwise; the
+/// file type tells directories, files and links apart. No particular
+/// order is guaranteed.
+///
+/// If the listing is cut short by the [`ListPolicy`], the last line
+/// is the object `{"truncated": true}`.
+#[instrument(err)]
+async fn api_list_stream(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    Policy(policy): Policy,
+    visibility: Option<Visibility>,
+) -> ApiResult<impl IntoResponse> {
+    // Measure the time now and round it down to the second
+    let now_sgnunixsec = DateTime::now().sgnunixsec();
+    let hider = visibility_or_default(visibility)
+        .in_dir(&*backend, &chroot, &vpath)
+        .await;
+    let hides = {
+        let vpath = vpath.clone();
+        move |md: &FileMetadata| {
+            let is_dir = md.file_type == FileType::Directory;
+            hider.hides(&vpath.join(&md.file_name), is_dir)
+        }
+    };
+
+    // Read the directory
+    let mut stream = match backend.list_directory(&chroot, &vpath).await {
+        Ok(stream) => stream,
+        Err(e) => match e.downcast::<std::io::Error>() {
+            Ok(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(ApiError::with_status(403)(e))
+            }
+            Ok(e) => return Err(ApiError::with_status(404)(e)),
+            Err(_) => {
+                return Err(ApiError::with_status(404)(anyhow!(
+                    "error building stream"
+                )))
+            }
+        },
+    };
+
+    let lines = async_stream::stream! {
+        let header = json!({
+            "version": "040",
+            "now": now_sgnunixsec,
+        });
+        yield Ok::<_, Error>(format!("{header}\n"));
+
+        let mut n = 0;
+        while let Some(md) = stream.next().await {
+            let Ok(md) = md else {
+                continue;
+            };
+            if md.raw_name.is_some() && !policy.lossy_names {
+                continue;
+            }
+            if hides(&md) {
+                continue;
+            }
+
+            // Stop at the limit
+            if n >= policy.max_entries {
+                yield Ok(format!("{}\n", json!({ "truncated": true })));
+                break;
+            }
+
+            // Follow links (if allowed), and skip whatever isn't a
+            // file, directory or link
+            let follow =
+                md.file_type == FileType::Link && policy.follow_symlinks;
+            let md = if follow {
+                let vpathf = vpath.join(&md.file_name);
+                let target = follow_get_md(&*backend, &chroot, &vpathf).await;
+                match target {
+                    Ok(target) => target,
+                    // Keep broken links (as links)
+                    Err(_) if is_broken_link(&*backend, &chroot, &vpathf)
+                        .await => md,
+                    Err(_) => continue,
+                }
+            } else {
+                md
+            };
+            if !matches!(
+                md.file_type,
+                FileType::RegularFile | FileType::Directory | FileType::Link
+            ) || hides(&md)
+            {
+                continue;
+            }
+
+            n += 1;
+            yield Ok(format!("{}\n", serfmeta(&md, now_sgnunixsec)));
+        }
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson; charset=utf-8")],
+        StreamBody::new(lines),
+    ))
+}
+
+/// Read a directory as [`read_entries`] does, by name
+async fn read_entry_map(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    vpath: &VirtualPath,
+    policy: &ListPolicy,
+    visibility: &Visibility,
+) -> ApiResult<HashMap<String, FileMetadata>> {
+    let (dirs, files, _) =
+        read_entries(backend, chroot, vpath, policy, visibility).await?;
+    Ok(dirs
+        .into_iter()
+        .chain(files)
+        .map(|md| (md.file_name.clone(), md))
+        .collect())
+}
+
+/// A permit to watch a directory (as an HTTP extension), held for as
+/// long as the watch lasts (see [`mw_limit_watchers`])
+#[derive(Debug)]
+struct WatchPermit(OwnedSemaphorePermit);
+
+/// Allow WatchPermit to be extracted (taken) from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for WatchPermit {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts.extensions.remove::<WatchPermit>().ok_or_else(|| {
+            ApiError::with_status(500)(anyhow!("watch permit not set"))
+        })
+    }
+}
+
+/// Limit the number of directories being watched at once to the
+/// number of permits of the semaphore, turning away the rest with
+/// 503 Service Unavailable. Set WatchPermit in the request.
+#[instrument(skip(req, next))]
+async fn mw_limit_watchers<B>(
+    State(permits): State<Arc<Semaphore>>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Ok(permit) = permits.try_acquire_owned() else {
+        let e = ApiError::with_status(503)(anyhow!("too many watchers"));
+        return ([(header::RETRY_AFTER, "5")], e).into_response();
+    };
+    req.extensions_mut().insert(WatchPermit(permit));
+    next.run(req).await
+}
+
+/// Turn away (with 403 Forbidden) requests from browsers on origins
+/// that the CORS policy doesn't allow. Any page may open a WebSocket,
+/// since CORS doesn't apply, so the origin must be checked like this.
+/// Requests without an `Origin` (not from browsers) pass.
+#[instrument(skip(req, next))]
+async fn mw_check_origin<B>(
+    State(cors): State<Arc<CorsPolicy>>,
+    req: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(origin) = req.headers().get(header::ORIGIN) {
+        if !cors.allows(origin) {
+            let e = ApiError::with_status(403)(anyhow!(
+                "origin not allowed: {origin:?}"
+            ));
+            return e.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// Watch a directory for changes, over a WebSocket
+///
+/// The real directory (on the local file system) is watched for
+/// changes to its entries. Once a change is seen, and (DEBOUNCEMS)
+/// milliseconds have passed for rapid changes to settle, the
+/// directory is read, as the list API reads it, and compared with the
+/// last reading. Each entry that appeared, disappeared, or changed
+/// (in type, size or last modified time) since is sent as one text
+/// message, like this:
+///
+/// ```
+/// {"event": ("created" | "removed" | "modified"),
+///  "now": (epoch, as in the list API),
+///  "entry": (the entry, encoded as in list API version "047")}
+/// ```
+///
+/// (For `removed`, the entry is as it was last seen.) If the
+/// directory can no longer be read, a message like
+/// `{"event": "error", "status": 404}` is sent, and the socket is
+/// closed. Messages from the client are ignored. The watch stops, and
+/// its permit (see [`mw_limit_watchers`]) is given back, when the
+/// socket closes.
+///
+/// Nothing is replayed across connections, so a client that
+/// reconnects should list the directory again.
+#[instrument(err, skip(ws))]
+async fn api_watch<const DEBOUNCEMS: u64>(
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    Policy(policy): Policy,
+    WatchPermit(permit): WatchPermit,
+    visibility: Option<Visibility>,
+    ws: WebSocketUpgrade,
+) -> ApiResult<Response> {
+    // Fail early (before upgrading) if it can't be read at all.
+    let visibility = visibility_or_default(visibility);
+    let mut last =
+        read_entry_map(&LocalFile, &chroot, &vpath, &policy, &visibility)
+            .await?;
+
+    // Watch the directory (but not those inside it), only to learn
+    // when to read it again.
+    let real_path = LocalFile
+        .canonicalize(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let (tx, mut changed) = tokio::sync::mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(
+        move |event: notify::Result<notify::Event>| {
+            // (Reading the directory is an access, too.)
+            if !matches!(&event, Ok(e) if e.kind.is_access()) {
+                _ = tx.try_send(());
+            }
+        },
+    )
+    .context("create watcher")
+    .map_err(ApiError::with_status(500))?;
+    watcher
+        .watch(&real_path, RecursiveMode::NonRecursive)
+        .with_context(|| format!("watch {real_path:?}"))
+        .map_err(ApiError::with_status(500))?;
+
+    Ok(ws.on_upgrade(move |mut socket| async move {
+        // Both go when the socket closes.
+        let (_permit, _watcher) = (permit, watcher);
+        loop {
+            tokio::select! {
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+                signal = changed.recv() => if signal.is_none() {
+                    break;
+                },
+            }
+            tokio::time::sleep(Duration::from_millis(DEBOUNCEMS)).await;
+            _ = changed.try_recv();
+
+            let now = read_entry_map(
+                &LocalFile,
+                &chroot,
+                &vpath,
+                &policy,
+                &visibility,
+            )
+            .await;
+            let now = match now {
+                Ok(now) => now,
+                Err(e) => {
+                    let msg = json!({
+                        "event": "error",
+                        "status": e.0.as_u16(),
+                    });
+                    _ = socket.send(Message::Text(msg.to_string())).await;
+                    break;
+                }
+            };
+
+            let epoch = DateTime::now().sgnunixsec();
+            let message = |name: &str, md: &FileMetadata| {
+                let entry = ListVersion::V047.serfmeta(md, epoch);
+                let msg =
+                    json!({ "event": name, "now": epoch, "entry": entry });
+                Message::Text(msg.to_string())
+            };
+            let mut messages = vec![];
+            for (name, md) in &now {
+                match last.get(name) {
+                    None => messages.push(message("created", md)),
+                    Some(was)
+                        if was.file_type != md.file_type
+                            || was.size != md.size
+                            || was.last_modified != md.last_modified =>
+                    {
+                        messages.push(message("modified", md))
+                    }
+                    Some(_) => {}
+                }
+            }
+            for (name, md) in &last {
+                if !now.contains_key(name) {
+                    messages.push(message("removed", md));
+                }
+            }
+            for msg in messages {
+                if socket.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            last = now;
+        }
+        _ = socket.close().await;
+    }))
+}
+
+/// Describe a single object as JSON
+///
+/// The response looks like this:
+///
+/// ```
+/// {
+///     "version": "040",
+///     "now": (epoch, as in the list API),
+///     "entry": (the object, encoded as in the list API),
+///     "target": ("fi" | "di" | "ln" | null),
+/// }
+/// ```
+///
+/// The `entry` describes the object itself, so a link is reported
+/// as a link (`"ln"`), with its own last modified time. For links,
+/// `target` is the type of whatever the link resolves to (inside the
+/// chroot), or `null` if it can't be resolved. For anything else,
+/// `target` is the same as the type in `entry`.
+#[instrument(err)]
+async fn api_stat(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+) -> ApiResult<impl IntoResponse> {
+    // Measure the time now and round it down to the second
+    let now_sgnunixsec = DateTime::now().sgnunixsec();
+
+    let md = backend
+        .read_link_metadata(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let target = if md.file_type == FileType::Link {
+        follow_get_md(&*backend, &chroot, &vpath)
+            .await
+            .ok()
+            .map(|md| sertype(md.file_type))
+    } else {
+        Some(sertype(md.file_type))
+    };
+
+    let value = json!({
+        "version": "040",
+        "now": now_sgnunixsec,
+        "entry": serfmeta(&md, now_sgnunixsec),
+        "target": target,
+    })
+    .to_string();
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        value,
+    ))
+}
+
+/// A disk usage, with the directory's last modified time and when it
+/// was added up
+type KnownUsage = (Option<DateTime>, Instant, DiskUsage);
+
+/// Disk usages of directories, remembered by virtual path
+#[derive(Debug, Clone, Default)]
+struct DiskUsages(Arc<Mutex<HashMap<PathBuf, KnownUsage>>>);
+
+impl DiskUsages {
+    /// Forget everything once this many directories are remembered
+    const CAPACITY: usize = 10_000;
+    /// Add up again after this long, even if the directory's last
+    /// modified time is the same (it doesn't change when files
+    /// deeper down do)
+    const TTL: Duration = Duration::from_secs(60);
+}
+
+/// Allow DiskUsages to be extracted from the request
+#[async_trait]
+impl axum::extract::FromRequestParts<()> for DiskUsages {
+    type Rejection = ApiError;
+
+    #[instrument]
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &(),
+    ) -> ApiResult<Self> {
+        parts
+            .extensions
+            .get::<DiskUsages>()
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::with_status(500)(anyhow!("disk usages not set"))
+            })
+    }
+}
+
+/// Set the DiskUsages in the request
+#[instrument(skip(req, next))]
+async fn mw_set_disk_usages<B>(
+    State(usages): State<DiskUsages>,
+    mut req: http::Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(usages);
+    next.run(req).await
+}
+
+/// Disk usage API
+///
+/// Add up the sizes of the files under a directory (see
+/// [`OpenFile::disk_usage`]), descending at most (DEPTH) levels and
+/// looking at most at (LIMIT) entries. Links aren't followed, and
+/// hidden paths (by name; see [`VisibilityPolicy`]) are left out. A
+/// file reports its own size.
+///
+/// The response looks like `{"bytes": N, "files": M, "truncated":
+/// bool}`. Results are remembered for a minute, or until the
+/// directory's own last modified time changes.
+#[instrument(err)]
+async fn api_du<const DEPTH: usize, const LIMIT: usize>(
+    usages: DiskUsages,
+    visibility: Option<Visibility>,
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+) -> ApiResult<impl IntoResponse> {
+    let md = backend
+        .read_metadata(&chroot, &vpath)
+        .await
+        .map_err(ApiError::with_status(404))?;
+    let known = usages.0.lock().unwrap().get(&*vpath).cloned();
+    let usage = match (md.file_type, known) {
+        (FileType::RegularFile, _) => DiskUsage {
+            bytes: md.size.unwrap_or_default(),
+            files: 1,
+            truncated: false,
+        },
+        (_, Some((lmo, at, usage)))
+            if lmo == md.last_modified && at.elapsed() < DiskUsages::TTL =>
+        {
+            usage
+        }
+        _ => {
+            let visibility = visibility_or_default(visibility);
+            let visible = |p: &VirtualPath| !visibility.policy.hides(p);
+            let usage = backend
+                .disk_usage(&chroot, &vpath, &visible, DEPTH, LIMIT)
+                .await
+                .map_err(|e| match e.downcast_ref::<std::io::Error>() {
+                    Some(ioe)
+                        if ioe.kind()
+                            == std::io::ErrorKind::PermissionDenied =>
+                    {
+                        ApiError::with_status(403)(e)
+                    }
+                    _ => ApiError::with_status(404)(e),
+                })?;
+            let mut usages = usages.0.lock().unwrap();
+            if usages.len() >= DiskUsages::CAPACITY {
+                usages.clear();
+            }
+            usages.insert(
+                vpath.to_path_buf(),
+                (md.last_modified, Instant::now(), usage),
+            );
+            usage
+        }
+    };
+
+    let value = json!({
+        "bytes": usage.bytes,
+        "files": usage.files,
+        "truncated": usage.truncated,
+    })
+    .to_string();
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        value,
+    ))
+}
+
+/// Count the entries of a directory, up to (LIMIT)
+///
+/// The response looks like `{"count": N, "truncated": bool}`, where
+/// `truncated` says whether counting stopped at the limit.
+#[instrument(err)]
+async fn api_count<const LIMIT: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+) -> ApiResult<impl IntoResponse> {
+    let (count, truncated) = backend
+        .count_directory(&chroot, &vpath, LIMIT)
+        .await
+        .map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(ioe) if ioe.kind() == std::io::ErrorKind::PermissionDenied => {
+                ApiError::with_status(403)(e)
+            }
+            _ => ApiError::with_status(404)(e),
+        })?;
+
+    let value = json!({
+        "count": count,
+        "truncated": truncated,
+    })
+    .to_string();
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+        value,
+    ))
+}
+
+/// Liveness probe
+///
+/// Always `200 OK`, as long as the server answers at all.
+async fn api_healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// Readiness probe
+///
+/// `200 OK` if the chroot can be described by the backend, and
+/// `503 Service Unavailable` otherwise.
+#[instrument(err)]
+async fn api_readyz(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+) -> ApiResult<impl IntoResponse> {
+    backend
+        .read_link_metadata(&chroot, &PathBuf::new())
+        .await
+        .context("describe chroot")
+        .map_err(ApiError::with_status(503))?;
+    Ok("ok")
+}
 
-        // Categorize
-        if md.file_type == FileType::RegularFile {
-            files.push(serfmeta(&md, now_sgnunixsec));
-            continue;
-        } else if md.file_type == FileType::Directory {
-            dirs.push(serfmeta(&md, now_sgnunixsec));
-            continue;
-        }
+/// Query parameters accepted by the search API
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    /// What to look for in the file names
+    q: String,
+    /// If `1`, interpret `q` as a glob pattern (case-insensitive)
+    /// instead of a case-insensitive substring.
+    glob: Option<String>,
+}
 
-        // Follow and then categorize. But, use the ORIGINAL metadata.
-        let vpathf = vpath.join(&md.file_name);
-        let md = follow_get_md(&chroot, &vpathf).await;
-        if md.is_err() {
-            continue;
+/// Search API
+///
+/// Recursively find objects under the virtual path whose names match
+/// the query, descending at most (DEPTH) levels and returning at most
+/// (LIMIT) results.
+///
+/// The response is shaped like that of the list API, except that
+/// each entry has a fifth item, the virtual path of the object
+/// (rooted, `/`), and that a `truncated` field says whether the
+/// result limit was hit.
+#[instrument(err)]
+async fn api_search<const DEPTH: usize, const LIMIT: usize>(
+    Backend(backend): Backend,
+    Chroot(chroot): Chroot,
+    VPath(vpath): VPath,
+    visibility: Option<Visibility>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<impl IntoResponse> {
+    // Build the matcher
+    let pred: Box<dyn Fn(&str) -> bool + Send + Sync> =
+        if query.glob.as_deref() == Some("1") {
+            let glob = GlobBuilder::new(&query.q)
+                .case_insensitive(true)
+                .build()
+                .context("compile glob")
+                .map_err(ApiError::with_status(400))?
+                .compile_matcher();
+            Box::new(move |name| glob.is_match(name))
+        } else {
+            let q = query.q.to_lowercase();
+            Box::new(move |name| name.to_lowercase().contains(&q))
+        };
+
+    // Measure the time now and round it down to the second
+    let now_sgnunixsec = DateTime::now().sgnunixsec();
+
+    // Walk, leaving out whatever is hidden
+    let visibility = visibility_or_default(visibility);
+    let visible = |hpath: &VirtualPath| !visibility.policy.hides(hpath);
+    let (hits, truncated) = search_directory(
+        &*backend, &*chroot, &*vpath, pred, visible, DEPTH, LIMIT,
+    )
+    .await
+    .map_err(ApiError::with_status(404))?;
+    let hits = drop_ignored(&visibility, &*backend, &chroot, hits).await;
+
+    // Categorize and serialize, appending the virtual path
+    let mut dirs = vec![];
+    let mut files = vec![];
+    for (hpath, md) in hits {
+        let mut value = serfmeta(&md, now_sgnunixsec);
+        let hpath = PathBuf::from("/").join(hpath);
+        if let Value::Array(a) = &mut value {
+            a.push(json!(hpath.to_str()));
         }
-        let md = md.unwrap();
-        if md.file_type == FileType::RegularFile {
-            files.push(serfmeta(&md, now_sgnunixsec));
-            continue;
-        } else if md.file_type == FileType::Directory {
-            dirs.push(serfmeta(&md, now_sgnunixsec));
-            continue;
+        match md.file_type {
+            FileType::RegularFile => files.push(value),
+            FileType::Directory => dirs.push(value),
+            _ => {}
         }
-        // If neither type even after following, ignore.
     }
 
-    // Append necessary metadata and then serialize
     let value = json!({
         "version": "040",
         "now": now_sgnunixsec,
+        "truncated": truncated,
         "dirs": dirs,
         "files": files,
     })
@@ -523,42 +5230,864 @@ async fn api_list(
 #[instrument]
 pub fn build_list_api(
     chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ListPolicy,
+    cors: CorsPolicy,
 ) -> axum::Router<(), axum::body::Body> {
-    axum::Router::new()
+    let router = axum::Router::new()
         .route("/*vpath", get(api_list))
         .route("/", get(api_list))
+        // A directory's own last modified time changes when entries
+        // are added, removed, or renamed (but not when an entry is
+        // modified in place, which is why clients must revalidate).
+        .layer(from_fn(mw_cache_http_reval_lmo))
+        .layer(from_fn_with_state(
+            CachePolicy::Revalidate,
+            mw_cache_control,
+        ))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_policy))
+        .layer(from_fn_with_state("list", mw_metrics));
+    // Outermost, so that preflights skip the rest
+    match cors.layer() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+/// Build a router for the streaming (NDJSON) list API
+#[instrument]
+pub fn build_list_stream_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ListPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", get(api_list_stream))
+        .route("/", get(api_list_stream))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_policy))
+}
+
+/// Build a router for watching directories for changes (see
+/// [`api_watch`]), at most (max_watchers) at once
+///
+/// It watches the local file system, whatever the backend.
+#[instrument]
+pub fn build_watch_api(
+    chroot: Arc<PathBuf>,
+    policy: ListPolicy,
+    cors: CorsPolicy,
+    max_watchers: usize,
+) -> axum::Router<(), axum::body::Body> {
+    let permits = Arc::new(Semaphore::new(max_watchers));
+    // Let changes settle for a quarter of a second.
+    axum::Router::new()
+        .route("/watch/*vpath", get(api_watch::<250>))
+        .route("/watch/", get(api_watch::<250>))
+        .route("/watch", get(api_watch::<250>))
+        .layer(from_fn_with_state(permits, mw_limit_watchers))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_policy))
+        .layer(from_fn_with_state(Arc::new(cors), mw_check_origin))
+        .layer(from_fn_with_state("watch", mw_metrics))
+}
+
+/// Build a router for the stat API (metadata of a single object)
+#[instrument]
+pub fn build_stat_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", get(api_stat))
+        .route("/", get(api_stat))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+}
+
+/// Build a router for the count API
+#[instrument]
+pub fn build_count_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    // Count at most 100,000 entries.
+    axum::Router::new()
+        .route("/*vpath", get(api_count::<100_000>))
+        .route("/", get(api_count::<100_000>))
         .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
         .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
         .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+}
+
+/// Build a router for the autoindex (plain HTML listing) API
+#[instrument]
+pub fn build_autoindex_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ListPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", get(api_autoindex))
+        .route("/", get(api_autoindex))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_policy))
+}
+
+/// Build a router for the health checks (`/healthz` and `/readyz`)
+/// and the metrics (`/metrics`)
+#[instrument(skip(metrics))]
+pub fn build_health_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    metrics: PrometheusHandle,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/healthz", get(api_healthz))
+        .route("/readyz", get(api_readyz))
+        .route("/metrics", get(api_metrics).with_state(metrics))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
 }
 
 /// Build a thumbnail server API
 #[instrument]
 pub fn build_thumb_api(
     chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ThumbPolicy,
+    cors: CorsPolicy,
 ) -> axum::Router<(), axum::body::Body> {
+    // Only the requests that get past the cache count toward the
+    // limit.
+    let permits = Arc::new(Semaphore::new(policy.max_in_flight));
+    let content_etag = policy.content_etag;
+    let policy = Arc::new(policy);
+
     // Use a limit (10 MB) for reading the file.
-    axum::Router::new()
+    let mut router = axum::Router::new()
         .route("/*vpath", get(api_thumb::<10>))
         .route("/", get(api_thumb::<10>))
+        .layer(from_fn_with_state(permits, mw_limit_in_flight))
+        .layer(from_fn_with_state(policy, mw_set_thumb_settings));
+    // The last modified time can't be trusted when hashing.
+    if !content_etag {
+        router = router.layer(from_fn(mw_cache_http_reval_lmo));
+    }
+    router = router.layer(from_fn(mw_cache_http_reval_etag));
+    if content_etag {
+        router = router.layer(from_fn_with_state(
+            ContentHashes::rehashing(),
+            mw_set_content_hashes,
+        ));
+    }
+    router = router
+        // (Unless versioned, in which case they never change.)
+        .layer(from_fn(mw_thumb_version))
+        // Thumbnails rarely change, so let them be cached for a day.
+        .layer(from_fn_with_state(
+            CachePolicy::MaxAge(86400),
+            mw_cache_control,
+        ))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("thumb", mw_metrics));
+    // Outermost, so that preflights skip the rest
+    match cors.layer() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+/// Build a router for the bulk thumbnail API (see [`api_thumbs`])
+#[instrument]
+pub fn build_thumbs_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ThumbPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    // The thumbnails themselves (not the requests) count toward the
+    // limit.
+    let permits = Arc::new(Semaphore::new(policy.max_in_flight));
+    let policy = Arc::new(policy);
+
+    // At most 100 paths per request, and a limit (10 MB) for reading
+    // each file
+    axum::Router::new()
+        .route("/", axum::routing::post(api_thumbs::<100, 10>))
+        .layer(from_fn_with_state(permits, mw_set_thumb_permits))
+        .layer(from_fn_with_state(policy, mw_set_thumb_settings))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("POST, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("thumbs", mw_metrics))
+}
+
+/// Build a router for the disk usage API (see [`api_du`])
+#[instrument]
+pub fn build_du_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    // Descend at most 32 levels and look at most at 100,000 entries.
+    axum::Router::new()
+        .route("/*vpath", get(api_du::<32, 100_000>))
+        .route("/", get(api_du::<32, 100_000>))
+        .layer(from_fn_with_state(
+            DiskUsages::default(),
+            mw_set_disk_usages,
+        ))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("du", mw_metrics))
+}
+
+/// Build a router for downloading several files as one archive (see
+/// [`api_archive`])
+///
+/// Only the [`DownloadPolicy::chunk_size`] applies.
+#[instrument]
+pub fn build_archive_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: DownloadPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    // At most 10,000 files per request
+    axum::Router::new()
+        .route("/", axum::routing::post(api_archive::<10_000>))
+        .layer(from_fn_with_state(policy.chunk_size, mw_set_chunk_size))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("POST, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("archive", mw_metrics))
+}
+
+/// Build a router for moving and renaming (see [`api_mv`])
+///
+/// This API (like those for deleting, uploading and making
+/// directories) changes files, so don't serve it unless the files are
+/// meant to be managed through the server.
+#[instrument]
+pub fn build_mv_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/", axum::routing::post(api_mv))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("POST, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("mv", mw_metrics))
+}
+
+/// Build a router for deleting (see [`api_delete`])
+///
+/// Like the move API ([`build_mv_api`]), don't serve it unless the
+/// files are meant to be managed through the server.
+#[instrument]
+pub fn build_delete_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", axum::routing::delete(api_delete))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("DELETE, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("delete", mw_metrics))
+}
+
+/// Build a router for uploading (see [`api_upload`])
+///
+/// Like the move API ([`build_mv_api`]), don't serve it unless the
+/// files are meant to be managed through the server.
+#[instrument]
+pub fn build_upload_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: UploadPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", axum::routing::put(api_upload))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_upload_settings))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("PUT, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("upload", mw_metrics))
+}
+
+/// Build a router for making directories (see [`api_mkdir`])
+///
+/// Like the move API ([`build_mv_api`]), don't serve it unless the
+/// files are meant to be managed through the server.
+#[instrument]
+pub fn build_mkdir_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    axum::Router::new()
+        .route("/*vpath", axum::routing::post(api_mkdir))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state("POST, OPTIONS", mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("mkdir", mw_metrics))
+}
+
+/// Build a router for the BlurHash API
+///
+/// Of the [`ThumbPolicy`], only the chunk size and the pool apply.
+#[instrument]
+pub fn build_blurhash_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+    policy: ThumbPolicy,
+) -> axum::Router<(), axum::body::Body> {
+    // Read at most 10 MB, like the thumbnails.
+    axum::Router::new()
+        .route("/*vpath", get(api_blurhash::<10>))
+        .route("/", get(api_blurhash::<10>))
+        .layer(from_fn_with_state(BlurHashes::default(), mw_set_blurhashes))
+        .layer(from_fn_with_state(Arc::new(policy), mw_set_thumb_settings))
+        .layer(from_fn(mw_cache_http_reval_lmo))
+        .layer(from_fn(mw_cache_http_reval_etag))
+        .layer(from_fn_with_state(
+            CachePolicy::MaxAge(86400),
+            mw_cache_control,
+        ))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("blurhash", mw_metrics))
+}
+
+/// Build a router for the dimensions API (the width and height of
+/// images)
+#[instrument]
+pub fn build_dimensions_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    // Read at most 10 MB, like the thumbnails.
+    axum::Router::new()
+        .route("/*vpath", get(api_dimensions::<10>))
+        .route("/", get(api_dimensions::<10>))
         .layer(from_fn(mw_cache_http_reval_lmo))
+        .layer(from_fn(mw_cache_http_reval_etag))
+        .layer(from_fn_with_state(
+            CachePolicy::MaxAge(86400),
+            mw_cache_control,
+        ))
         .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
         .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
         .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+        .layer(from_fn_with_state("dimensions", mw_metrics))
 }
 
 /// Build a download server API
+///
+/// Directories are downloaded as archives (ZIP or tar.gz), or as
+/// their index file if the [`DownloadPolicy`] names one.
 #[instrument]
 pub fn build_download_api(
     chroot: Arc<PathBuf>,
+    policy: DownloadPolicy,
 ) -> axum::Router<(), axum::body::Body> {
-    let servedir =
-        ServeDir::new(chroot.as_ref()).append_index_html_on_directories(false);
+    assert!(policy.chunk_size > 0, "expect a positive chunk size");
+    let servedir = ServeDir::new(chroot.as_ref())
+        .append_index_html_on_directories(false)
+        .with_buf_chunk_size(policy.chunk_size);
 
-    axum::Router::new()
+    let mut router = axum::Router::new()
         .route("/*vpath", get_service(servedir.clone()))
-        .route("/", get_service(servedir))
+        .route("/", get_service(servedir));
+    // (Inside of the archives, which have their own type.)
+    if !policy.content_types.is_empty() {
+        let types = policy
+            .content_types
+            .into_iter()
+            .map(|(ext, content_type)| {
+                let content_type = HeaderValue::from_str(&content_type)
+                    .expect("expect a MIME type to be a valid header value");
+                (ext.to_ascii_lowercase(), content_type)
+            })
+            .collect::<HashMap<_, _>>();
+        router =
+            router.layer(from_fn_with_state(Arc::new(types), mw_content_types));
+    }
+    router = router
+        // Descend at most 32 levels and take at most 100,000 objects.
+        .layer(from_fn(mw_archive_directories::<32, 100_000>))
+        .layer(from_fn(mw_download_filename));
+    if let Some(index_file) = policy.index_file {
+        assert!(
+            !index_file.is_empty() && !index_file.contains('/'),
+            "expect the index file to be a plain file name"
+        );
+        router = router
+            .layer(from_fn_with_state(Arc::from(index_file), mw_serve_index));
+    }
+    router = router.layer(from_fn(mw_cache_http_reval_etag));
+    if policy.content_etag {
+        router = router.layer(from_fn_with_state(
+            ContentHashes::default(),
+            mw_set_content_hashes,
+        ));
+    }
+    router
+        .layer(from_fn_with_state(
+            CachePolicy::Revalidate,
+            mw_cache_control,
+        ))
+        .layer(from_fn_with_state(policy.chunk_size, mw_set_chunk_size))
+        .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
+        .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
+        .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state("download", mw_metrics))
+}
+
+/// Build a search server API
+#[instrument]
+pub fn build_search_api(
+    chroot: Arc<PathBuf>,
+    backend: Arc<dyn OpenFile>,
+) -> axum::Router<(), axum::body::Body> {
+    // Descend at most 16 levels and return at most 1,000 results.
+    axum::Router::new()
+        .route("/*vpath", get(api_search::<16, 1000>))
+        .route("/", get(api_search::<16, 1000>))
         .layer(from_fn(mw_guard_virt_path))
+        .layer(from_fn(mw_limit_uri_len::<MAX_URI_LEN, _>))
         .layer(from_fn(mw_nosniff))
+        .layer(from_fn_with_state(ALLOW_GET, mw_options))
         .layer(from_fn_with_state(chroot, mw_set_chroot))
+        .layer(from_fn_with_state(backend, mw_set_backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::mem::*;
+
+    /// Last modified time of the test image (UNIX time, seconds)
+    const MTIME: u64 = 1_700_000_000;
+
+    /// A thumbnail API over a file system (in memory) with a small
+    /// image in it, last modified `nanos` into [`MTIME`]
+    fn thumb_api(nanos: u32) -> (Arc<MemFile>, axum::Router) {
+        let mut png = std::io::Cursor::new(vec![]);
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mem = Arc::new(MemFile::default());
+        mem.insert("a.png", MemNode::File(png.into_inner()));
+        touch(&mem, nanos);
+        let router = build_thumb_api(
+            Arc::new(PathBuf::from("/")),
+            mem.clone(),
+            ThumbPolicy::default(),
+            CorsPolicy::default(),
+        )
+        .layer(from_fn_with_state(
+            Visibility::new(VisibilityPolicy::default()),
+            mw_set_visibility,
+        ));
+        (mem, router)
+    }
+
+    /// Set the last modified time of the image to `nanos` into
+    /// [`MTIME`]
+    fn touch(mem: &MemFile, nanos: u32) {
+        let lmo = UNIX_EPOCH + Duration::new(MTIME, nanos);
+        mem.set_last_modified("a.png", lmo.into());
+    }
+
+    /// Get the thumbnail with these (conditional) headers
+    async fn get_thumb(
+        router: &axum::Router,
+        headers: &[(header::HeaderName, &str)],
+    ) -> Response {
+        let mut req = http::Request::get("/a.png");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        router.clone().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn thumb_last_modified_round_trips() {
+        let (_mem, router) = thumb_api(500_000_000);
+
+        let res = get_thumb(&router, &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let lmo = res.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        assert_eq!(lmo, "Tue, 14 Nov 2023 22:13:20 GMT");
+
+        // The client sends back what it was given
+        let res = get_thumb(&router, &[(header::IF_MODIFIED_SINCE, lmo)]).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.into_body().data().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn thumb_fresh_within_the_same_second() {
+        let (_mem, router) = thumb_api(999_999_999);
+
+        // Equal, down to the second (which is all HTTP dates carry)
+        let same = "Tue, 14 Nov 2023 22:13:20 GMT";
+        let res =
+            get_thumb(&router, &[(header::IF_MODIFIED_SINCE, same)]).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        // A second earlier is stale
+        let before = "Tue, 14 Nov 2023 22:13:19 GMT";
+        let res =
+            get_thumb(&router, &[(header::IF_MODIFIED_SINCE, before)]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn thumb_stale_after_a_change_within_the_same_second() {
+        let (mem, router) = thumb_api(100_000_000);
+        let res = get_thumb(&router, &[]).await;
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_owned();
+        let lmo = res.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        let lmo = lmo.to_owned();
+
+        // Modified again, in the same second as the client's copy
+        touch(&mem, 900_000_000);
+        let res = get_thumb(
+            &router,
+            &[
+                (header::IF_NONE_MATCH, &etag),
+                (header::IF_MODIFIED_SINCE, &lmo),
+            ],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers()[header::ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn blurhash_of_undecodable_image_is_unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.png"), "not an image").unwrap();
+        let router = build_blurhash_api(
+            Arc::new(dir.path().to_path_buf()),
+            Arc::new(LocalFile),
+            ThumbPolicy::default(),
+        )
+        .layer(from_fn_with_state(
+            Visibility::new(VisibilityPolicy::default()),
+            mw_set_visibility,
+        ));
+
+        let req = http::Request::get("/a.png").body(Body::empty()).unwrap();
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn delete_removes_a_broken_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("a");
+        std::os::unix::fs::symlink("nowhere", &link).unwrap();
+        let router = build_delete_api(
+            Arc::new(dir.path().to_path_buf()),
+            Arc::new(LocalFile),
+        )
+        .layer(from_fn_with_state(
+            Visibility::new(VisibilityPolicy::default()),
+            mw_set_visibility,
+        ));
+        let delete = |path: &str| {
+            let req = http::Request::delete(path).body(Body::empty()).unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let res = delete("/a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(std::fs::symlink_metadata(&link).is_err());
+        let res = delete("/a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Names of the entries, in order, once sorted
+    fn sorted(
+        sort: &str,
+        names: impl IntoIterator<Item = String>,
+    ) -> Vec<String> {
+        let mut mds: Vec<_> = names
+            .into_iter()
+            .map(|file_name| FileMetadata {
+                file_type: FileType::RegularFile,
+                file_name,
+                size: None,
+                last_modified: None,
+                mode: None,
+                created: None,
+                accessed: None,
+                raw_name: None,
+            })
+            .collect();
+        sort.parse::<ListSort>().unwrap().apply(&mut mds);
+        mds.into_iter().map(|md| md.file_name).collect()
+    }
+
+    #[test]
+    fn random_sort_with_a_seed_is_repeatable() {
+        let names = (0..32).map(|i| format!("{i:02}"));
+        let shuffled = sorted("random:42", names.clone());
+
+        // Whatever order the directory was read in
+        assert_eq!(sorted("random:42", names.clone().rev()), shuffled);
+        // Actually shuffled, and differently for another seed
+        assert_ne!(shuffled, names.clone().collect::<Vec<_>>());
+        assert_ne!(sorted("random:43", names), shuffled);
+    }
+
+    #[tokio::test]
+    async fn passed_credentials_are_remembered() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let auth = Arc::new(BasicAuth::new("me".into(), hash));
+        let basic =
+            |creds: &str| format!("Basic {}", BASE64_STANDARD.encode(creds));
+
+        assert!(!auth.clone().verify(basic("me:hunter3")).await.unwrap());
+        assert!(auth.passed.lock().unwrap().is_empty());
+        assert!(auth.clone().verify(basic("me:hunter2")).await.unwrap());
+        assert_eq!(auth.passed.lock().unwrap().len(), 1);
+
+        // Remembered, so it passes even with no permits left
+        let _all = auth.checking.clone().acquire_many_owned(4).await;
+        assert!(auth.clone().verify(basic("me:hunter2")).await.unwrap());
+        let e = auth.clone().verify(basic("me:hunter3")).await.unwrap_err();
+        assert_eq!(e.into_response().status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn loopback_is_limited_unless_exempt() {
+        for exempt_loopback in [false, true] {
+            let limiter = ClientLimiter::new(ClientPolicy {
+                max_in_flight_per_ip: 1,
+                exempt_loopback,
+            });
+            let router = axum::Router::new()
+                .route("/", get(|| async { "hello" }))
+                .layer(from_fn_with_state(limiter, mw_limit_per_client));
+            let get_root = || {
+                let mut req =
+                    http::Request::get("/").body(Body::empty()).unwrap();
+                req.extensions_mut()
+                    .insert(ClientIp("127.0.0.1".parse().unwrap()));
+                router.clone().oneshot(req)
+            };
+
+            // (The first body is still held.)
+            let _first = get_root().await.unwrap();
+            let second = get_root().await.unwrap();
+            let status = match exempt_loopback {
+                false => StatusCode::TOO_MANY_REQUESTS,
+                true => StatusCode::OK,
+            };
+            assert_eq!(second.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_permit_is_held_until_the_body_is_sent() {
+        let limiter = ClientLimiter::new(ClientPolicy::default());
+        let router = axum::Router::new()
+            .route("/", get(|| async { "hello" }))
+            .layer(from_fn_with_state(limiter.clone(), mw_limit_per_client));
+        let mut req = http::Request::get("/").body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ClientIp("203.0.113.7".parse().unwrap()));
+
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(res.body().size_hint().exact(), Some(5));
+        assert_eq!(limiter.in_flight.lock().unwrap().len(), 1);
+        drop(res);
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn access_log_keeps_the_size_and_hides_signatures() {
+        /// A log to look at afterwards
+        #[derive(Clone, Default)]
+        struct Lines(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                std::io::Write::write(&mut *self.0.lock().unwrap(), buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Lines::default();
+        let router = axum::Router::new()
+            .route("/a.png", get(|| async { "hello" }))
+            .layer(from_fn_with_state(
+                Some(AccessLog::to(lines.clone())),
+                mw_access_log,
+            ));
+        let req = http::Request::get("/a.png?exp=1700000000&sig=c2VjcmV0")
+            .body(Body::empty())
+            .unwrap();
+
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(res.body().size_hint().exact(), Some(5));
+        let mut body = res.into_body();
+        while body.data().await.is_some() {}
+        drop(body);
+        let lines = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            lines.contains(
+                "\"GET /a.png?exp=1700000000&sig=REDACTED HTTP/1.1\" 200 5 "
+            ),
+            "{lines}"
+        );
+        assert!(!lines.contains("c2VjcmV0"), "{lines}");
+    }
+
+    #[tokio::test]
+    async fn dropped_upload_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = build_upload_api(
+            Arc::new(dir.path().to_path_buf()),
+            Arc::new(LocalFile),
+            UploadPolicy::default(),
+        )
+        .layer(from_fn_with_state(
+            Visibility::new(VisibilityPolicy::default()),
+            mw_set_visibility,
+        ));
+        let (mut tx, body) = Body::channel();
+        let req = http::Request::put("/a.txt").body(body).unwrap();
+
+        // Send some of the body, and then give up on the request
+        tx.send_data("hello".into()).await.unwrap();
+        let upload = router.clone().oneshot(req);
+        let given_up =
+            tokio::time::timeout(Duration::from_millis(200), upload).await;
+        assert!(given_up.is_err());
+        let left: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert!(left.is_empty(), "{left:?}");
+
+        // (Whereas one that's seen through is placed.)
+        let req = http::Request::put("/a.txt").body("hello".into()).unwrap();
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let left: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(left.len(), 1);
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn attachment_names_are_encoded() {
+        let value = attachment_utf8("a \"b\"\u{1}é.zip");
+        assert_eq!(
+            value.to_str().unwrap(),
+            "attachment; filename*=UTF-8''a%20%22b%22%01%C3%A9.zip"
+        );
+    }
+
+    #[tokio::test]
+    async fn watchers_are_capped_and_origins_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let cors = CorsPolicy {
+            allowed_origins: vec!["https://app.example.com".into()],
+            ..Default::default()
+        };
+        let watch = |max_watchers, origin| {
+            let router = build_watch_api(
+                Arc::new(dir.path().to_owned()),
+                ListPolicy::default(),
+                cors.clone(),
+                max_watchers,
+            );
+            let req = http::Request::get("/watch/")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap();
+            router.oneshot(req)
+        };
+
+        let res = watch(0, "https://app.example.com").await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = watch(1, "https://evil.example.com").await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
 }
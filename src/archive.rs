@@ -0,0 +1,259 @@
+//! Archiving
+//!
+//! Build archives of many files on the fly, streaming them out as
+//! they are being written, so that the whole archive never has to
+//! sit in memory or on disk.
+
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
+
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::{
+    tokio::write::ZipFileWriter, Compression, ZipDateTimeBuilder,
+    ZipEntryBuilder,
+};
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_tar::{EntryType, Header};
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+
+use crate::{
+    fs::{OpenFile, RealPath},
+    prim::*,
+};
+
+/// Size of the in-memory pipe between the archiver and the reader
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Archive formats that can be produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// ZIP, with deflate where it helps
+    Zip,
+    /// Gzip-compressed tarball (POSIX tar with GNU extensions)
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// MIME type of the archive
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+        }
+    }
+
+    /// Conventional file name extension, without the leading dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+        }
+    }
+}
+
+/// An object to put into an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Virtual path to read the object from (through the backend)
+    pub virt_path: PathBuf,
+    /// Path of the object inside the archive, separated by `/`
+    pub name: String,
+    /// Whether it's a directory (which has no content)
+    pub is_dir: bool,
+    /// Size in bytes, as of when the entry was gathered
+    pub size: Option<u64>,
+    /// Last modified
+    pub last_modified: Option<DateTime>,
+}
+
+/// Whether a file is likely compressed already, judging by the
+/// extension of its name, in which case compressing it again only
+/// wastes CPU time.
+fn is_compressed(name: &str) -> bool {
+    let ext = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    matches!(
+        ext.as_deref(),
+        Some(
+            "jpg"
+                | "jpeg"
+                | "png"
+                | "gif"
+                | "webp"
+                | "avif"
+                | "heic"
+                | "mp3"
+                | "aac"
+                | "ogg"
+                | "opus"
+                | "flac"
+                | "mp4"
+                | "m4a"
+                | "m4v"
+                | "mkv"
+                | "webm"
+                | "mov"
+                | "avi"
+                | "zip"
+                | "gz"
+                | "tgz"
+                | "bz2"
+                | "xz"
+                | "zst"
+                | "br"
+                | "7z"
+                | "rar"
+                | "docx"
+                | "xlsx"
+                | "pptx"
+                | "odt"
+                | "epub"
+                | "jar"
+                | "apk"
+        )
+    )
+}
+
+/// Convert a [`DateTime`] to the MS-DOS-style date used by ZIP
+fn zip_date(dt: &DateTime) -> Option<async_zip::ZipDateTime> {
+    let dt = OffsetDateTime::from_unix_timestamp(dt.sgnunixsec()).ok()?;
+    Some(
+        ZipDateTimeBuilder::new()
+            .year(dt.year())
+            .month(dt.month() as u32)
+            .day(dt.day() as u32)
+            .hour(dt.hour() as u32)
+            .minute(dt.minute() as u32)
+            .second(dt.second() as u32)
+            .build(),
+    )
+}
+
+/// Write the entries into a ZIP archive
+async fn write_zip(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    entries: Vec<ArchiveEntry>,
+    writer: DuplexStream,
+) -> Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for entry in entries {
+        let mut builder = if entry.is_dir {
+            ZipEntryBuilder::new(
+                format!("{}/", entry.name).into(),
+                Compression::Stored,
+            )
+        } else if is_compressed(&entry.name) {
+            ZipEntryBuilder::new(entry.name.clone().into(), Compression::Stored)
+        } else {
+            ZipEntryBuilder::new(
+                entry.name.clone().into(),
+                Compression::Deflate,
+            )
+        };
+        if let Some(date) = entry.last_modified.as_ref().and_then(zip_date) {
+            builder = builder.last_modification_date(date);
+        }
+
+        if entry.is_dir {
+            zip.write_entry_whole(builder, &[])
+                .await
+                .context("write directory entry")?;
+            continue;
+        }
+
+        let mut file = backend
+            .open_file(chroot, &entry.virt_path)
+            .await
+            .with_context(|| format!("open {:?}", entry.virt_path))?;
+        let mut w = zip
+            .write_entry_stream(builder)
+            .await
+            .context("begin file entry")?
+            .compat_write();
+        tokio::io::copy(&mut file, &mut w)
+            .await
+            .with_context(|| format!("copy {:?}", entry.virt_path))?;
+        w.into_inner().close().await.context("end file entry")?;
+    }
+    zip.close().await.context("finish archive")?;
+    Ok(())
+}
+
+/// Write the entries into a gzip-compressed tarball
+async fn write_tar_gz(
+    backend: &dyn OpenFile,
+    chroot: &RealPath,
+    entries: Vec<ArchiveEntry>,
+    writer: DuplexStream,
+) -> Result<()> {
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(writer));
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        let mtime = entry.last_modified.map(|dt| dt.sgnunixsec());
+        header.set_mtime(mtime.unwrap_or_default().max(0) as u64);
+
+        if entry.is_dir {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            tar.append_data(&mut header, &entry.name, tokio::io::empty())
+                .await
+                .context("write directory entry")?;
+            continue;
+        }
+
+        // The header comes first, so the size is the one gathered
+        // beforehand. Should the file have grown since, the excess is
+        // cut off so that the archive stays intact.
+        let size = entry
+            .size
+            .with_context(|| format!("size unknown: {:?}", entry.virt_path))?;
+        let file = backend
+            .open_file(chroot, &entry.virt_path)
+            .await
+            .with_context(|| format!("open {:?}", entry.virt_path))?;
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(size);
+        tar.append_data(&mut header, &entry.name, file.take(size))
+            .await
+            .with_context(|| format!("copy {:?}", entry.virt_path))?;
+    }
+    let mut gz = tar.into_inner().await.context("finish archive")?;
+    gz.shutdown().await.context("finish compression")?;
+    Ok(())
+}
+
+/// Start writing an archive of the entries in the background, reading
+/// them through `backend`, and return the reading end.
+///
+/// For ZIP, files that are already compressed (judging by the
+/// extension) are stored as they are. Others are deflated.
+///
+/// If anything goes wrong in the middle, the error is logged and the
+/// archive ends abruptly, since there is no way to tell the client
+/// after the response has begun.
+#[instrument(skip(backend, entries), fields(n = entries.len()))]
+pub fn archive_stream(
+    backend: Arc<dyn OpenFile>,
+    chroot: Arc<PathBuf>,
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+) -> impl AsyncRead + Send {
+    let (reader, writer) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::spawn(async move {
+        let (backend, chroot) = (&*backend, &**chroot);
+        let res = match format {
+            ArchiveFormat::Zip => {
+                write_zip(backend, chroot, entries, writer).await
+            }
+            ArchiveFormat::TarGz => {
+                write_tar_gz(backend, chroot, entries, writer).await
+            }
+        };
+        if let Err(e) = res {
+            tracing::warn!("archive_stream ({format:?}): {e:?}");
+        }
+    });
+    reader
+}
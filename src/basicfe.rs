@@ -1,6 +1,6 @@
 //! Basic front-end
 //!
-//! (No thumbnails, list view)
+//! (List view, with thumbnails)
 //!
 //! Also, an example of relying on the JSON responses and HTTP status
 //! codes and not on the source code of the back-end.
@@ -9,26 +9,34 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use async_trait::async_trait;
 use axum::{
     body::Body,
-    extract::{FromRequestParts, State},
+    extract::{FromRequestParts, Query, State},
+    http::{
+        header::{ACCEPT, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
     http::{request::Parts, Request},
-    http::{HeaderValue, StatusCode},
-    middleware::{from_fn_with_state, Next},
+    middleware::{from_fn, from_fn_with_state, Next},
     response::IntoResponse,
     response::Response,
     routing::get,
     Router,
 };
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use reqwest::Url;
 use sailfish::TemplateOnce;
-use serde::Serialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use thiserror::Error;
-use time::{format_description::FormatItem, macros::format_description};
+use time::{
+    format_description::{parse_owned, OwnedFormatItem},
+    UtcOffset,
+};
 
 use crate::prim::*;
 
@@ -123,25 +131,84 @@ impl StatusCodeExt for StatusCode {
 struct StatCodeTemplate {
     code: u16,
     canonical: &'static str,
+    /// What it means for the user
+    message: &'static str,
 }
 
-/// Turn it into an Axum response
+/// Marks a response as made from a [`BasicError`], so that
+/// [`mw_error_format`] may put it in another format
+#[derive(Debug, Clone, Copy)]
+struct ErrorPage;
+
+/// Turn it into an Axum response (an HTML page; see
+/// [`mw_error_format`] for the other formats)
 impl IntoResponse for BasicError {
     fn into_response(self) -> Response {
-        (
+        let message = match self.code {
+            StatusCode::NOT_FOUND => "There is nothing here.",
+            code if code.is_server_error() => {
+                "Something went wrong on our end. Please try again later."
+            }
+            _ => "The request could not be handled.",
+        };
+        let mut res = (
             self.code,
-            [("Content-Type", "text/html")],
+            [("Content-Type", "text/html; charset=utf-8")],
             StatCodeTemplate {
                 code: self.code.as_u16(),
                 canonical: self.code.canonical_reason().unwrap_or_default(),
+                message,
             }
             .render_once()
             .expect(
                 "Expect error to be rendered properly due to \
 static template",
             ),
+        )
+            .into_response();
+        res.extensions_mut().insert(ErrorPage);
+        res
+    }
+}
+
+/// Send error pages (see [`BasicError`]) as JSON or plain text instead
+/// of HTML to clients that ask for those (by `Accept`) and not for
+/// HTML. The status code is kept.
+#[instrument(skip(req, next))]
+async fn mw_error_format<B>(req: Request<B>, next: Next<B>) -> Response {
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let res = next.run(req).await;
+    if res.extensions().get::<ErrorPage>().is_none()
+        || accept.contains("text/html")
+    {
+        return res;
+    }
+    let code = res.status();
+    let canonical = code.canonical_reason().unwrap_or_default();
+    if accept.contains("application/json") {
+        let body = json!({
+            "error": { "status": code.as_u16(), "message": canonical }
+        });
+        (
+            code,
+            [("Content-Type", "application/json; charset=utf-8")],
+            body.to_string(),
+        )
+            .into_response()
+    } else if accept.contains("text/plain") {
+        (
+            code,
+            [("Content-Type", "text/plain; charset=utf-8")],
+            format!("{} {canonical}\n", code.as_u16()),
         )
             .into_response()
+    } else {
+        res
     }
 }
 
@@ -158,6 +225,23 @@ struct Item {
     size_with_units: String,
     /// Last modified time of the item
     last_modified: String,
+    /// How long ago the item was last modified, such as "2 hours ago"
+    time_ago: String,
+    /// Where to get a thumbnail of the item (empty for directories,
+    /// which get an icon instead)
+    thumb_href: String,
+}
+
+/// Define a column header that sorts the listing when clicked
+#[derive(Serialize, Debug)]
+struct SortLink {
+    /// Name of the column
+    label: &'static str,
+    /// Where to go to sort by the column (a query string)
+    href: String,
+    /// Arrow showing the current order, if sorted by the column
+    /// (empty if not)
+    arrow: &'static str,
 }
 
 /// Define a page to be used as a template
@@ -165,34 +249,105 @@ struct Item {
 #[template(path = "basic.html")]
 struct Page {
     root: String,
+    /// Where the parent directory is (empty at the root)
+    parent: String,
     time: String,
+    sort_links: Vec<SortLink>,
     directories: Vec<Item>,
     files: Vec<Item>,
 }
 
-/// Format a date and time in UNIX time for presentation to US English
-/// speakers.
-fn format_unix_timestamp(ts: i64) -> Result<String> {
-    static FORMAT: &[FormatItem<'_>] =
-        format_description!("[year]-[month]-[day] GMT");
+/// How to present dates and sizes
+#[derive(Debug, Clone)]
+pub struct DisplayConfig {
+    /// Time zone to show dates in, as an offset from UTC
+    pub utc_offset: UtcOffset,
+    /// How to format dates, in the format description language of
+    /// the `time` crate (version 2), such as
+    /// `[year]-[month]-[day] [hour]:[minute]`
+    pub date_format: String,
+    /// Show sizes in SI units (powers of 1000: kB, MB, ...) instead
+    /// of binary ones (powers of 1024: KB, MB, ...)
+    pub si_units: bool,
+}
 
-    Ok(time::OffsetDateTime::from_unix_timestamp(ts)
-        .context("convert UNIX timestamp to date")?
-        .format(FORMAT)
-        .unwrap())
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            utc_offset: UtcOffset::UTC,
+            date_format: "[year]-[month]-[day] GMT".to_string(),
+            si_units: false,
+        }
+    }
 }
 
-/// Format the number of bytes into a human readable string for US
-/// English speakers.
-fn format_size_bytes(n: u64) -> String {
-    let mut n = n as f64;
-    let mut i = 0;
-    let units = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
-    while n >= 1024.0 {
-        n /= 1024.0;
-        i += 1;
+/// A [`DisplayConfig`], ready to use
+#[derive(Debug)]
+struct FormatRules {
+    /// See [`DisplayConfig::utc_offset`]
+    utc_offset: UtcOffset,
+    /// See [`DisplayConfig::date_format`] (parsed)
+    date_format: OwnedFormatItem,
+    /// See [`DisplayConfig::si_units`]
+    si_units: bool,
+}
+
+impl FormatRules {
+    /// Parse the date format of the configuration
+    fn new(config: &DisplayConfig) -> Result<Self> {
+        let date_format = parse_owned::<2>(&config.date_format)
+            .context("parse the date format")?;
+        Ok(Self {
+            utc_offset: config.utc_offset,
+            date_format,
+            si_units: config.si_units,
+        })
+    }
+
+    /// Format a date and time in UNIX time.
+    fn date(&self, ts: i64) -> Result<String> {
+        time::OffsetDateTime::from_unix_timestamp(ts)
+            .context("convert UNIX timestamp to date")?
+            .to_offset(self.utc_offset)
+            .format(&self.date_format)
+            .context("format date")
+    }
+
+    /// Format the number of bytes into a human readable string.
+    fn size(&self, n: u64) -> String {
+        format_size_bytes(n, self.si_units)
+    }
+}
+
+/// Say roughly how long ago something happened, given its age in
+/// seconds, for US English speakers (e.g., "3 minutes ago").
+fn format_age(age: i64) -> String {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    const MONTH: i64 = 30 * DAY;
+    const YEAR: i64 = 365 * DAY;
+
+    let ago = |n: i64, unit: &str| {
+        let s = if n == 1 { "" } else { "s" };
+        format!("{n} {unit}{s} ago")
+    };
+    // (Clocks may disagree a little, so the age may be negative.)
+    if age < MINUTE {
+        "just now".to_string()
+    } else if age < HOUR {
+        ago(age / MINUTE, "minute")
+    } else if age < DAY {
+        ago(age / HOUR, "hour")
+    } else if age < 2 * DAY {
+        "yesterday".to_string()
+    } else if age < MONTH {
+        ago(age / DAY, "day")
+    } else if age < YEAR {
+        ago(age / MONTH, "month")
+    } else {
+        ago(age / YEAR, "year")
     }
-    format!("{:.2} {}", n, units[i])
 }
 
 /// Define each metadata file as in the JSON response.
@@ -202,11 +357,11 @@ struct ApiFileMetadata {
     name: String,
     /// File size in bytes, if present.
     size: Option<u64>,
-    /// Last modified time in UNIX time offset.
+    /// Last modified time in UNIX time offset (age).
     ///
-    /// The API gives out a "now" value, another UNIX timestamp,
-    /// to find the correct last modified time---the value of adding both
-    /// together.
+    /// The API gives out a "now" value, another UNIX timestamp, and
+    /// each age is "now" minus the last modified time. So, to find
+    /// the last modified time, subtract the age from "now".
     ///
     /// Follow the code to learn more.
     last_modified: Option<i64>,
@@ -244,36 +399,141 @@ fn deser_api_file_metadata(array: &Value) -> Result<ApiFileMetadata> {
 /// - `base`: Rooted (`/`) path. Such as `/Pictures/great neat pics`.
 /// - `now`: The "now" field from the JSON response.
 /// - `meta`: The metadata of the file.
+/// - `tbu`: The thumbnail server base URL, if the item is to have a
+///   thumbnail (that is, if it's not a directory).
+/// - `formats`: How to present dates and sizes.
 fn show_api_file_metadata(
     base: &Path,
     now: i64,
     meta: ApiFileMetadata,
+    tbu: Option<&Url>,
+    formats: &FormatRules,
 ) -> Result<Item> {
     let href = base
         .join(&meta.name)
         .to_str()
-        .ok_or_else(|| anyhow!("path not UTF-8"))?
-        .to_owned();
+        .ok_or_else(|| anyhow!("path not UTF-8"))
+        .map(encode_path)?;
+    let thumb_href = tbu
+        .map(|tbu| tbu.join(href.trim_start_matches('/')))
+        .transpose()
+        .context("join the path to thumb server base url")?
+        .map(|mut url| {
+            // Address the thumbnail by the version of the file, so
+            // that it can be cached for good.
+            if let (Some(path), Some(age)) =
+                (base.join(&meta.name).to_str(), meta.last_modified)
+            {
+                let version = file_version(path, meta.size, Some(now - age));
+                url.query_pairs_mut().append_pair("v", &version);
+            }
+            String::from(url)
+        })
+        .unwrap_or_default();
     let name = meta.name;
     let size_with_units = meta
         .size
-        .map(format_size_bytes)
+        .map(|n| formats.size(n))
         .unwrap_or_else(|| "".to_owned());
     let last_modified = meta
         .last_modified
-        .map(|ts| ts + now)
-        .map(format_unix_timestamp)
-        .transpose()
-        .expect("convert UNIX timestamp to date")
+        .map(|age| formats.date(now - age))
+        .transpose()?
         .unwrap_or_else(|| "".to_owned());
+    let time_ago = meta.last_modified.map(format_age).unwrap_or_default();
     Ok(Item {
         href,
         name,
         size_with_units,
         last_modified,
+        time_ago,
+        thumb_href,
     })
 }
 
+/// Query parameters accepted by the front-end
+#[derive(Debug, Deserialize)]
+struct FrontQuery {
+    /// Show only the files with these extensions (comma-separated,
+    /// case-insensitive), such as `jpg,png,gif`
+    ext: Option<String>,
+    /// Show only the files of this type (see [`extensions_of_type`])
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// Order of the listing, passed on to the list API as is (such as
+    /// `mtime:desc`)
+    sort: Option<String>,
+}
+
+/// Make the column headers, each linking to the same page sorted by
+/// its column, ascending, or descending if already sorted ascending.
+/// The filters are kept.
+fn sort_links(query: &FrontQuery) -> Vec<SortLink> {
+    // Current order
+    let (key, dir) = match query.sort.as_deref() {
+        Some(sort) => sort.split_once(':').unwrap_or((sort, "asc")),
+        None => ("", ""),
+    };
+    // Filters to keep
+    let mut keep = String::new();
+    for (name, value) in [("ext", &query.ext), ("type", &query.kind)] {
+        if let Some(value) = value {
+            let value = utf8_percent_encode(value, NON_ALPHANUMERIC);
+            keep.push_str(&format!("&{name}={value}"));
+        }
+    }
+    [("Name", "name"), ("Size", "size"), ("Modified", "mtime")]
+        .into_iter()
+        .map(|(label, column)| {
+            let (next, arrow) = match (key == column, dir) {
+                (true, "asc") => ("desc", "\u{25B2}"),
+                (true, "desc") => ("asc", "\u{25BC}"),
+                _ => ("asc", ""),
+            };
+            SortLink {
+                label,
+                href: format!("?sort={column}:{next}{keep}"),
+                arrow,
+            }
+        })
+        .collect()
+}
+
+/// Extensions of the files of a type: `image`, `video`, or `audio`
+fn extensions_of_type(kind: &str) -> Option<&'static [&'static str]> {
+    match kind {
+        "image" => Some(&[
+            "avif", "bmp", "gif", "heic", "jpeg", "jpg", "png", "svg", "tif",
+            "tiff", "webp",
+        ]),
+        "video" => Some(&["avi", "m4v", "mkv", "mov", "mp4", "webm", "wmv"]),
+        "audio" => Some(&["aac", "flac", "m4a", "mp3", "ogg", "opus", "wav"]),
+        _ => None,
+    }
+}
+
+/// Gather the (lowercase) extensions of the files to show, or `None`
+/// to show all files.
+fn file_filter(query: &FrontQuery) -> Result<Option<Vec<String>>> {
+    if query.ext.is_none() && query.kind.is_none() {
+        return Ok(None);
+    }
+    let mut exts = vec![];
+    if let Some(ext) = &query.ext {
+        exts.extend(
+            ext.split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty()),
+        );
+    }
+    if let Some(kind) = &query.kind {
+        let of_type = extensions_of_type(kind)
+            .ok_or_else(|| anyhow!("unknown type: {kind:?}"))?;
+        exts.extend(of_type.iter().map(|e| e.to_string()));
+    }
+    Ok(Some(exts))
+}
+
 /// The download server's base URL
 ///
 /// (Used to be called `DownloadServerOrigin`, so you might see `dso`
@@ -345,6 +605,73 @@ async fn mw_inject_lbu<B>(
     next.run(req).await
 }
 
+/// Thumb service base URL
+#[derive(Debug, Clone)]
+struct ThumbBaseUrl(Arc<Url>);
+
+/// Extract [`ThumbBaseUrl`] from the request.
+#[async_trait]
+impl FromRequestParts<()> for ThumbBaseUrl {
+    type Rejection = BasicError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &(),
+    ) -> BasicResult<Self> {
+        let tbu = parts.extensions.get::<ThumbBaseUrl>();
+        if tbu.is_none() {
+            // Since TBU is our custom type, if we expect it but it doesn't
+            // actually exist, it's our fault (logic error).
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+        Ok(tbu.unwrap().clone())
+    }
+}
+
+/// Inject a [`ThumbBaseUrl`] into the request from the
+/// given argument.
+async fn mw_inject_tbu<B>(
+    state_tbu: State<ThumbBaseUrl>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(state_tbu.0);
+    next.run(req).await
+}
+
+/// How to present dates and sizes
+#[derive(Debug, Clone)]
+struct Formats(Arc<FormatRules>);
+
+/// Extract [`Formats`] from the request.
+#[async_trait]
+impl FromRequestParts<()> for Formats {
+    type Rejection = BasicError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &(),
+    ) -> BasicResult<Self> {
+        let formats = parts.extensions.get::<Formats>();
+        if formats.is_none() {
+            // Since Formats is our custom type, if we expect it but it
+            // doesn't actually exist, it's our fault (logic error).
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+        Ok(formats.unwrap().clone())
+    }
+}
+
+/// Inject [`Formats`] into the request from the given argument.
+async fn mw_inject_formats<B>(
+    state_formats: State<Formats>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    req.extensions_mut().insert(state_formats.0);
+    next.run(req).await
+}
+
 /// An HTTP Client connection pool.
 ///
 /// See: [`reqwest::Client`].
@@ -381,14 +708,40 @@ async fn mw_inject_http_client<B>(
     next.run(req).await
 }
 
+/// Decide what to respond with when the list service responds with
+/// something other than 200 OK (or 404 Not Found, which is handled
+/// separately).
+///
+/// Problems with the request (4xx) are passed on as they are, and so
+/// is 503 Service Unavailable. Other failures of the list service are
+/// 502 Bad Gateway, so that they can be told apart from failures of
+/// the front-end itself (500).
+fn frontend_status(backend: StatusCode) -> StatusCode {
+    match backend {
+        StatusCode::SERVICE_UNAVAILABLE => backend,
+        s if s.is_server_error() => StatusCode::BAD_GATEWAY,
+        s if s.is_client_error() => s,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
 /// Serve the HTTP (web) interface.
 #[instrument(skip(client), err)]
+#[allow(clippy::too_many_arguments)] // (extractors)
 async fn api(
     lbu: ListBaseUrl,
     dbu: DownloadBaseUrl,
+    tbu: ThumbBaseUrl,
+    formats: Formats,
     client: Client,
+    headers: HeaderMap,
+    Query(query): Query<FrontQuery>,
     path: Option<axum::extract::Path<PathBuf>>,
 ) -> BasicResult<Response> {
+    // Decide which files to show. (The list API doesn't filter, so
+    // it's done here.)
+    let filter = file_filter(&query).with_status(StatusCode::BAD_REQUEST)?;
+
     // When the route is called without an argument declared at startup,
     // the path will be None. That is to mean the root directory.
     let path = path.map(|p| p.0).unwrap_or_else(|| PathBuf::from("/"));
@@ -410,20 +763,34 @@ async fn api(
         .to_str()
         .ok_or_err("path not UTF-8")
         .with_status(StatusCode::BAD_REQUEST)?;
+    // Encode it, so that it's not taken for anything but a path.
+    let path = &encode_path(path);
     // Join the path with the list server base URL.
-    let url = lbu
+    let mut url = lbu
         .0
         .join(path)
         .context("join the path to list server base url")
         .with_status(StatusCode::BAD_REQUEST)?;
-    // Make the request to the LIST service.
-    let resp = client
-        .0
-        .get(url.clone())
-        .send()
-        .await
-        .context("make the request to list service")
-        .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Pass on the order, if any.
+    if let Some(sort) = &query.sort {
+        url.query_pairs_mut().append_pair("sort", sort);
+    }
+    // Make the request to the LIST service, passing on the
+    // credentials, if any (in case it asks for them).
+    let mut req = client.0.get(url.clone());
+    if let Some(authorization) = headers.get(AUTHORIZATION) {
+        req = req.header(AUTHORIZATION, authorization);
+    }
+    // If it can't be reached (or doesn't answer in time), it's the
+    // gateway's problem, not ours.
+    let resp = req.send().await.map_err(|e| BasicError {
+        code: if e.is_timeout() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else {
+            StatusCode::BAD_GATEWAY
+        },
+        err: Some(Error::new(e).context("make the request to list service")),
+    })?;
     // Inspect the status code.
     let status = resp.status();
     // If 404, it could actually be a file not a directory. In that
@@ -441,9 +808,18 @@ async fn api(
         )
             .into_response());
     }
-    // If not 200, then it's an error.
+    // If not 200, then it's an error. Pass on the headers that tell
+    // the client what to do about it (log in, or come back later).
     if status != StatusCode::OK {
-        return Err(status.annotate("response not 200"));
+        tracing::debug!("list service responded with {status}");
+        let mut forward = HeaderMap::new();
+        for name in [WWW_AUTHENTICATE, RETRY_AFTER] {
+            if let Some(value) = resp.headers().get(&name) {
+                forward.insert(name, value.clone());
+            }
+        }
+        let code = frontend_status(status);
+        return Ok((forward, code.annotate("response not 200")).into_response());
     }
 
     // Fetch the JSON, and then interpret the result.
@@ -478,7 +854,9 @@ async fn api(
         .ok_or_err("now not integer")
         .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
     // Display "now" as a date.
-    let now_display = format_unix_timestamp(now)
+    let now_display = formats
+        .0
+        .date(now)
         .context("format UNIX timestamp 'now' field")
         .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
     // Grab the JSON array named "files," and then convert those into
@@ -495,9 +873,24 @@ async fn api(
         let meta = deser_api_file_metadata(json_file)
             .context("deserialize API file metadata")
             .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
-        let meta = show_api_file_metadata(&url_base_path, now, meta)
-            .context("show API file metadata")
-            .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Some(filter) = &filter {
+            let ext = Path::new(&meta.name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase());
+            if !ext.is_some_and(|ext| filter.contains(&ext)) {
+                continue;
+            }
+        }
+        let meta = show_api_file_metadata(
+            &url_base_path,
+            now,
+            meta,
+            Some(&tbu.0),
+            &formats.0,
+        )
+        .context("show API file metadata")
+        .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
         files.push(meta);
     }
     // Do the same with "directories," except that the JSON field is
@@ -514,17 +907,26 @@ async fn api(
         let meta = deser_api_file_metadata(json_dir)
             .context("deserialize API directory metadata")
             .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
-        let meta = show_api_file_metadata(&url_base_path, now, meta)
-            .context("show API directory metadata")
-            .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let meta =
+            show_api_file_metadata(&url_base_path, now, meta, None, &formats.0)
+                .context("show API directory metadata")
+                .with_status(StatusCode::INTERNAL_SERVER_ERROR)?;
         directories.push(meta);
     }
 
     // Format the page
 
+    // Link to the parent directory, unless at the root.
+    let parent = url_base_path
+        .parent()
+        .map(|parent| encode_path(&parent.to_string_lossy()))
+        .unwrap_or_default();
+
     let page = Page {
         root: url_base_path.to_string_lossy().to_string(),
+        parent,
         time: now_display,
+        sort_links: sort_links(&query),
         files,
         directories,
     };
@@ -548,6 +950,22 @@ pub struct BasicFrontend {
     pub download_base_url: String,
     /// The list server base URL
     pub list_base_url: String,
+    /// The thumb server base URL
+    pub thumb_base_url: String,
+    /// How long to wait for a connection to a back-end service
+    pub connect_timeout: Duration,
+    /// How long to wait for a whole request to a back-end service
+    pub request_timeout: Duration,
+    /// Most idle connections to keep open for each back-end service
+    pub pool_max_idle_per_host: usize,
+    /// How often to send TCP keep-alive probes on those connections
+    pub tcp_keepalive: Duration,
+    /// How to present dates and sizes
+    pub display: DisplayConfig,
+    /// Secret to send with every request to a back-end service, for
+    /// those that accept only requests from the front-end (see
+    /// [`crate::api::mw_internal_only`])
+    pub internal_secret: Option<String>,
 }
 
 /// Serve
@@ -561,12 +979,117 @@ pub fn build_api_basicfe(config: &BasicFrontend) -> Router<(), Body> {
         .expect("expect the list base URL to be valid");
     let lbu = ListBaseUrl(Arc::new(lbu));
 
-    let client = Client(reqwest::Client::new());
+    let tbu = Url::from_str(&config.thumb_base_url)
+        .expect("expect the thumb base URL to be valid");
+    let tbu = ThumbBaseUrl(Arc::new(tbu));
+
+    let formats = FormatRules::new(&config.display)
+        .expect("expect the display configuration to be valid");
+    let formats = Formats(Arc::new(formats));
+
+    let mut headers = HeaderMap::new();
+    if let Some(secret) = &config.internal_secret {
+        let mut secret = HeaderValue::from_str(secret)
+            .expect("expect the internal secret to be a valid header value");
+        secret.set_sensitive(true);
+        headers.insert(crate::api::INTERNAL_SECRET_HEADER, secret);
+    }
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.request_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .tcp_keepalive(config.tcp_keepalive)
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "-basicfe/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .expect("expect the HTTP client to be built");
+    let client = Client(client);
 
     Router::new()
         .route("/*path", get(api))
         .route("/", get(api))
         .layer(from_fn_with_state(lbu, mw_inject_lbu))
         .layer(from_fn_with_state(dbu, mw_inject_dso))
+        .layer(from_fn_with_state(tbu, mw_inject_tbu))
+        .layer(from_fn_with_state(formats, mw_inject_formats))
         .layer(from_fn_with_state(client, mw_inject_http_client))
+        .layer(from_fn(mw_error_format))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use percent_encoding::percent_decode_str;
+
+    use super::*;
+    use crate::fs::{FileMetadata, FileType};
+
+    #[test]
+    fn last_modified_round_trips() {
+        let mtime = 1_700_000_000;
+        let now = mtime + 3 * 24 * 60 * 60;
+        let md = FileMetadata {
+            file_type: FileType::RegularFile,
+            file_name: "a.txt".to_string(),
+            size: Some(1),
+            last_modified: Some(
+                (UNIX_EPOCH + Duration::from_secs(mtime as u64)).into(),
+            ),
+            mode: None,
+            created: None,
+            accessed: None,
+            raw_name: None,
+        };
+        let formats = FormatRules::new(&DisplayConfig {
+            date_format: "[year]-[month]-[day] [hour]:[minute]:[second]"
+                .to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Encoded by the list API, decoded by the front-end
+        let array = crate::api::serfmeta(&md, now);
+        let meta = deser_api_file_metadata(&array).unwrap();
+        let item =
+            show_api_file_metadata(Path::new("/"), now, meta, None, &formats)
+                .unwrap();
+        assert_eq!(item.last_modified, "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn links_survive_odd_names() {
+        let formats = FormatRules::new(&DisplayConfig::default()).unwrap();
+        let tbu = Url::parse("http://127.0.0.1:2998").unwrap();
+        let meta = ApiFileMetadata {
+            name: "c#d?e.txt".to_string(),
+            size: Some(1),
+            last_modified: Some(0),
+        };
+        let item = show_api_file_metadata(
+            Path::new("/a b"),
+            0,
+            meta,
+            Some(&tbu),
+            &formats,
+        )
+        .unwrap();
+        assert_eq!(item.href, "/a%20b/c%23d%3Fe.txt");
+
+        // The thumbnail URL keeps the whole name in its path
+        let thumb = Url::parse(&item.thumb_href).unwrap();
+        assert_eq!(thumb.path(), "/a%20b/c%23d%3Fe.txt");
+        assert_eq!(thumb.fragment(), None);
+        assert!(thumb.query().unwrap().starts_with("v="));
+
+        // The services get the name back, and accept it
+        let path = percent_decode_str(&item.href).decode_utf8().unwrap();
+        assert_eq!(path, "/a b/c#d?e.txt");
+        assert!(!crate::fs::bad_path1(path.trim_start_matches('/')));
+    }
 }
@@ -5,7 +5,10 @@
 //! - Defining the metadata for an object
 //! - Reading said metadata
 //! - Listing files in a directory as an asynchronous stream
+//! - Searching a directory tree for file names
 //! - Canonicalizing a file by following links
+//! - Opening files through a pluggable backend ([`OpenFile`])
+//! - Caching what a backend says for a short time ([`CachedFile`])
 //! - Deciding heuristically whether a file path is invalid
 //!
 //! On the metadata side, the file name and some rest of the
@@ -13,17 +16,61 @@
 //! convenient, since [`std::fs::Metadata`] doesn't have the file name.
 
 use std::{
+    collections::{HashMap, VecDeque},
+    ffi::OsStr,
     fmt::Debug,
     path::{Component, Path, PathBuf},
     pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
 use async_stream::try_stream;
-use tokio_stream::Stream;
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::prim::*;
 
+/// Tunable rules for [`bad_path1_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PathPolicy {
+    /// Longest admissible path, in bytes (depends on encoding)
+    pub max_len: usize,
+    /// Whether to reject the names reserved by Windows (`CON`, `PRN`,
+    /// `AUX`, `NUL`, `COM1`-`COM9` and `LPT1`-`LPT9`, regardless of
+    /// case or extension, so `aux.tar.gz` too)
+    pub windows_reserved_names: bool,
+    /// Whether to reject paths that aren't valid UTF-8
+    pub require_utf8: bool,
+}
+
+impl Default for PathPolicy {
+    /// The rules used by [`bad_path1`]
+    fn default() -> Self {
+        Self {
+            max_len: 2048,
+            windows_reserved_names: false,
+            require_utf8: true,
+        }
+    }
+}
+
+/// Whether a path component is a name reserved by Windows
+fn windows_reserved_name(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end().to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            let (prefix, digit) = stem.split_at(stem.len().min(3));
+            matches!(prefix, "COM" | "LPT")
+                && matches!(digit.as_bytes(), [b'1'..=b'9'])
+        }
+    }
+}
+
 /// Determine whether a file path has prohibited characters
 /// or other restricted parts.
 ///
@@ -32,16 +79,32 @@ use crate::prim::*;
 /// - Longer than 2,048 bytes (depends on encoding)
 /// - Invalid UTF-8
 /// - ASCII control characters
-/// - `/ < > : " / \ | ? *`
+/// - `/ < > : " / \ | *`
 /// - Non-normal paths (such as `..`, `.` or `//`)
 ///
 /// It's possible that the longest path on Windows that is
 /// admitted by this algorithm is significantly shorter than
 /// what is admitted under Unix-like platforms due to the encoding.
 ///
+/// `?` is admitted (though Windows refuses it), since links and URLs
+/// carry it percent-encoded, so it's never taken for a query there.
+///
 /// On empty paths (""): returns `false`, which means that it is valid.
-#[instrument(skip(p), fields(osstrlen = p.as_ref().as_os_str().len()))]
+///
+/// To use other rules, see [`bad_path1_with`].
 pub fn bad_path1(p: impl AsRef<Path> + Debug) -> bool {
+    bad_path1_with(&PathPolicy::default(), p)
+}
+
+/// Like [`bad_path1`], but under the given [`PathPolicy`].
+///
+/// If the policy doesn't require UTF-8, the character checks are done
+/// on the lossy UTF-8 representation of each component.
+#[instrument(skip(p), fields(osstrlen = p.as_ref().as_os_str().len()))]
+pub fn bad_path1_with(
+    policy: &PathPolicy,
+    p: impl AsRef<Path> + Debug,
+) -> bool {
     let p: &Path = p.as_ref();
 
     // Early pass for empty paths
@@ -56,7 +119,7 @@ pub fn bad_path1(p: impl AsRef<Path> + Debug) -> bool {
     // Unix-like platforms, you could use .as_bytes().len() instead,
     // (.as_bytes() being defined on Unix-like platforms only),
     // but that wouldn't work on Windows.
-    if p.as_os_str().len() > 2048 {
+    if p.as_os_str().len() > policy.max_len {
         tracing::trace!("Path too long, reject");
         return true;
     }
@@ -78,7 +141,7 @@ pub fn bad_path1(p: impl AsRef<Path> + Debug) -> bool {
 
     // Invalid UTF-8 check. Also get a UTF-8 representation.
     let sp = p.to_str();
-    if sp.is_none() {
+    if sp.is_none() && policy.require_utf8 {
         tracing::trace!("Path not valid UTF-8, reject.");
         return true;
     }
@@ -88,7 +151,7 @@ pub fn bad_path1(p: impl AsRef<Path> + Debug) -> bool {
     for component in p.components() {
         if let Component::Normal(component) = component {
             let component2 = component.to_str();
-            if component2.is_none() {
+            if component2.is_none() && policy.require_utf8 {
                 // This is a highly unusual situation that should be
                 // alerted to the user. Crafted string?
                 tracing::warn!(
@@ -97,7 +160,18 @@ though whole path is UTF-8. Reject."
                 );
                 return true;
             }
-            let component = component2.unwrap();
+            let component = component.to_string_lossy();
+            let component = component.as_ref();
+
+            // Names reserved by Windows, if asked
+            if policy.windows_reserved_names && windows_reserved_name(component)
+            {
+                tracing::trace!(
+                    "Component is a name reserved by Windows, reject. \
+Component: {component:?}"
+                );
+                return true;
+            }
 
             // Control characters or Windows-specific bad characters, but
             // enforced for all platforms anyway
@@ -105,7 +179,7 @@ though whole path is UTF-8. Reject."
                 c.is_ascii_control()
                     || matches!(
                         c,
-                        '/' | '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*'
+                        '/' | '<' | '>' | ':' | '"' | '\\' | '|' | '*'
                     )
             });
             if let Some(c) = filter.into_iter().next() {
@@ -185,6 +259,16 @@ pub struct FileMetadata {
     pub size: Option<u64>,
     /// Last modified
     pub last_modified: Option<DateTime>,
+    /// Permission bits (such as `0o755`), on Unix
+    pub mode: Option<u32>,
+    /// Created, where the platform and the file system know
+    pub created: Option<DateTime>,
+    /// Last accessed, where the platform and the file system know
+    pub accessed: Option<DateTime>,
+    /// The file name as raw bytes, if it isn't UTF-8, in which case
+    /// `file_name` is only a lossy rendition of it (see
+    /// [`list_directory`])
+    pub raw_name: Option<Vec<u8>>,
 }
 
 /// Convert a pair of the UTF-8 file name and native [Metadata](std::fs::Metadata)
@@ -214,22 +298,63 @@ where
             bail!("unknown file type");
         };
         let lmo = fme.modified().map(|st| st.into()).ok();
+        let cre = fme.created().map(|st| st.into()).ok();
+        let acc = fme.accessed().map(|st| st.into()).ok();
+        #[cfg(unix)]
+        let mode = Some(std::os::unix::fs::MetadataExt::mode(&fme) & 0o7777);
+        #[cfg(not(unix))]
+        let mode = None;
         Ok(Self {
             file_type: fty,
             file_name: fna,
             size: fsi,
             last_modified: lmo,
+            mode,
+            created: cre,
+            accessed: acc,
+            raw_name: None,
         })
     }
 }
 
+/// A stream of [`FileMetadata`]s (though with the possibility of
+/// errors), as returned by [`list_directory`].
+pub type FileMetadataStream =
+    Pin<Box<dyn Stream<Item = Result<FileMetadata>> + Send>>;
+
+/// Make a displayable (but lossy) UTF-8 file name out of one that
+/// isn't UTF-8, along with the raw bytes of the name, so that it can
+/// still be told apart from others. (Unix only. Elsewhere, it's an
+/// error.)
+#[cfg(unix)]
+fn lossy_name(name: &OsStr) -> Result<(String, Option<Vec<u8>>)> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok((
+        name.to_string_lossy().into_owned(),
+        Some(name.as_bytes().into()),
+    ))
+}
+
+/// Make a displayable (but lossy) UTF-8 file name out of one that
+/// isn't UTF-8, along with the raw bytes of the name, so that it can
+/// still be told apart from others. (Unix only. Elsewhere, it's an
+/// error.)
+#[cfg(not(unix))]
+fn lossy_name(_name: &OsStr) -> Result<(String, Option<Vec<u8>>)> {
+    bail!("file name bad utf-8")
+}
+
 /// Asynchronously list a directory, returning a stream of
 /// [`FileMetadata`]s (though with the possibility of errors).
+///
+/// Entries whose names aren't UTF-8 are given a lossy name and carry
+/// the raw one in [`FileMetadata::raw_name`]. It's up to the caller
+/// to skip them if it needs real names.
 #[instrument]
 pub async fn list_directory(
     chroot: impl AsRef<RealPath> + Debug + Send + Sync,
     virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
-) -> Result<Pin<Box<dyn Stream<Item = Result<FileMetadata>> + Send>>> {
+) -> Result<FileMetadataStream> {
     let read_dir =
         tokio::fs::read_dir(chroot.as_ref().join(virt_path.as_ref()))
             .await
@@ -240,21 +365,118 @@ pub async fn list_directory(
             // Find the file name
             let de = de
                 .context("get directory entry")?;
-            let fna = de
-                .file_name()
-                .to_str()
-                .ok_or_else(|| anyhow!("file name bad utf-8"))?
-                .to_string();
+            let fna = de.file_name();
+            let (fna, raw) = match fna.to_str() {
+                Some(fna) => (fna.to_string(), None),
+                None => lossy_name(&fna)?,
+            };
             // Find the metadata
             let md = de.metadata().await.context("get metadata")?;
             // Go
-            let md: FileMetadata = (fna, md).try_into()?;
+            let mut md: FileMetadata = (fna, md).try_into()?;
+            md.raw_name = raw;
             yield md;
         }
     };
     Ok(Box::pin(read_dir))
 }
 
+/// Count the entries of a directory, stopping at `max`.
+///
+/// Returns the count and whether it was cut short (`truncated`).
+///
+/// This is much cheaper than [`list_directory`] because no metadata
+/// is read, but for the same reason it counts every entry, including
+/// those that [`list_directory`] would fail to describe.
+#[instrument(err)]
+pub async fn count_directory(
+    chroot: impl AsRef<RealPath> + Debug + Send + Sync,
+    virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
+    max: usize,
+) -> Result<(usize, bool)> {
+    let mut read_dir =
+        tokio::fs::read_dir(chroot.as_ref().join(virt_path.as_ref()))
+            .await
+            .context("open read_dir")?;
+    let mut count = 0;
+    while read_dir
+        .next_entry()
+        .await
+        .context("get directory entry")?
+        .is_some()
+    {
+        if count == max {
+            return Ok((count, true));
+        }
+        count += 1;
+    }
+    Ok((count, false))
+}
+
+/// Remove a file, a link, or an empty directory, or (if `recursive`)
+/// a directory and everything in it.
+///
+/// Returns the number of objects removed. Links are removed as
+/// themselves and never followed, so nothing outside of the directory
+/// is touched. The chroot itself (an empty virtual path) is refused.
+#[instrument(err)]
+pub async fn remove(
+    chroot: impl AsRef<RealPath> + Debug + Send + Sync,
+    virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
+    recursive: bool,
+) -> Result<u64> {
+    if virt_path.as_ref().as_os_str().is_empty() {
+        return Err(anyhow!("refuse to remove the chroot"));
+    }
+    let real_path = chroot.as_ref().join(virt_path.as_ref());
+    let md = tokio::fs::symlink_metadata(&real_path)
+        .await
+        .context("get metadata")?;
+    if !md.is_dir() {
+        tokio::fs::remove_file(&real_path)
+            .await
+            .context("remove file")?;
+        return Ok(1);
+    }
+    if !recursive {
+        tokio::fs::remove_dir(&real_path)
+            .await
+            .context("remove directory")?;
+        return Ok(1);
+    }
+
+    // Depth first: list each directory the first time it's seen, and
+    // remove it the second time (once it's been emptied).
+    let mut count = 0;
+    let mut stack = vec![(real_path, false)];
+    while let Some((path, emptied)) = stack.pop() {
+        if emptied {
+            tokio::fs::remove_dir(&path)
+                .await
+                .context("remove directory")?;
+            count += 1;
+            continue;
+        }
+        stack.push((path.clone(), true));
+        let mut read_dir =
+            tokio::fs::read_dir(&path).await.context("open read_dir")?;
+        while let Some(entry) =
+            read_dir.next_entry().await.context("get directory entry")?
+        {
+            // (The file type of an entry is that of the link, if so.)
+            if entry.file_type().await.context("get file type")?.is_dir() {
+                stack.push((entry.path(), false));
+            } else {
+                tokio::fs::remove_file(entry.path())
+                    .await
+                    .context("remove file")?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
 /// Read the metadata of an individual file
 #[instrument(err)]
 pub async fn read_metadata(
@@ -280,13 +502,572 @@ pub async fn read_metadata(
     (fna, md).try_into()
 }
 
+/// Read the metadata of an individual object without following it if
+/// it's a link (so that links are reported as such)
+///
+/// Unlike [`read_metadata`], the chroot itself (an empty virtual
+/// path) is accepted and given an empty name.
+#[instrument(err)]
+pub async fn read_link_metadata(
+    chroot: impl AsRef<RealPath> + Debug + Send + Sync,
+    virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
+) -> Result<FileMetadata> {
+    // Find the file name
+    let fna = virt_path
+        .as_ref()
+        .file_name()
+        .map(|fna| fna.to_str().ok_or_else(|| anyhow!("bad utf-8")))
+        .transpose()?
+        .unwrap_or_default()
+        .to_string();
+
+    // Get the metadata
+    let real_path = chroot.as_ref().join(virt_path.as_ref());
+    let md = tokio::fs::symlink_metadata(real_path)
+        .await
+        .context("get metadata")?;
+
+    // Convert the metadata to a FileMetadata
+    (fna, md).try_into()
+}
+
+/// Deepest virtual path (in components) that [`canonicalize`] will
+/// try to resolve
+pub const MAX_DEPTH: usize = 64;
+
+/// Whether the error comes from a loop of links (`ELOOP`), as
+/// opposed to something that doesn't exist
+pub fn is_link_loop(e: &Error) -> bool {
+    #[cfg(unix)]
+    let eloop = Some(libc::ELOOP);
+    #[cfg(not(unix))]
+    let eloop = None;
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| eloop.is_some() && e.raw_os_error() == eloop)
+}
+
 /// Canonicalize a path by accessing the file system
+///
+/// Virtual paths deeper than [`MAX_DEPTH`] are not found, without
+/// touching the file system, to bound the work. (Links along the way
+/// are bounded by the operating system, which gives up on loops; see
+/// [`is_link_loop`].)
 #[instrument]
 pub async fn canonicalize(
     chroot: impl AsRef<RealPath> + Debug + Send + Sync,
     virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
 ) -> Result<PathBuf> {
+    let depth = virt_path.as_ref().components().count();
+    if depth > MAX_DEPTH {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .context(format!("path too deep ({depth})"));
+    }
     let real_path = chroot.as_ref().join(virt_path.as_ref());
-    let real_path = tokio::fs::canonicalize(real_path).await?;
-    Ok(real_path)
+    match tokio::fs::canonicalize(real_path)
+        .await
+        .map_err(Error::from)
+    {
+        Err(e) if is_link_loop(&e) => Err(e.context("link loop")),
+        real_path => real_path,
+    }
+}
+
+/// A file opened for reading by an [`OpenFile`] backend
+pub trait TokioFile: AsyncRead + Send + Unpin {}
+
+impl<T: AsyncRead + Send + Unpin> TokioFile for T {}
+
+/// A storage backend that can open files and describe them
+///
+/// The free functions of this module always use the local file
+/// system. Code that should work with other kinds of storage goes
+/// through this trait instead.
+#[async_trait]
+pub trait OpenFile: Debug + Send + Sync {
+    /// Open a file for reading
+    async fn open_file(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<Box<dyn TokioFile>>;
+
+    /// Read the metadata of an individual file
+    async fn read_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata>;
+
+    /// Read the metadata of an individual object without following
+    /// it if it's a link. The chroot itself is accepted.
+    ///
+    /// Backends without links can rely on the default, which is the
+    /// same as [`OpenFile::read_metadata`].
+    async fn read_link_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        self.read_metadata(chroot, virt_path).await
+    }
+
+    /// Canonicalize a path by following links
+    async fn canonicalize(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<PathBuf>;
+
+    /// List a directory
+    async fn list_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadataStream>;
+
+    /// Count the entries of a directory, stopping at `max`. Returns
+    /// the count and whether it was cut short.
+    ///
+    /// The default counts what [`OpenFile::list_directory`] yields.
+    async fn count_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+        max: usize,
+    ) -> Result<(usize, bool)> {
+        let mut stream = self.list_directory(chroot, virt_path).await?;
+        let mut count = 0;
+        while stream.next().await.is_some() {
+            if count == max {
+                return Ok((count, true));
+            }
+            count += 1;
+        }
+        Ok((count, false))
+    }
+
+    /// Add up the sizes of the regular files under a directory,
+    /// breadth-first, descending at most `max_depth` levels and
+    /// looking at no more than `max_entries` entries. If either stops
+    /// the walk short, `truncated` is set.
+    ///
+    /// Links are not followed (nor counted), so the walk never leaves
+    /// the tree. Paths rejected by `visible` are skipped, along with
+    /// everything under them. Subdirectories that can't be read are
+    /// skipped too.
+    async fn disk_usage(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+        visible: &(dyn for<'p> Fn(&'p VirtualPath) -> bool + Send + Sync),
+        max_depth: usize,
+        max_entries: usize,
+    ) -> Result<DiskUsage> {
+        let mut usage = DiskUsage::default();
+        let mut entries = 0;
+        let mut queue = VecDeque::from([(virt_path.to_path_buf(), 0)]);
+        while let Some((dir, depth)) = queue.pop_front() {
+            let mut stream = match self.list_directory(chroot, &dir).await {
+                Ok(stream) => stream,
+                Err(e) if dir == virt_path => return Err(e),
+                Err(_) => continue,
+            };
+            while let Some(md) = stream.next().await {
+                let Ok(md) = md else {
+                    continue;
+                };
+                if entries == max_entries {
+                    usage.truncated = true;
+                    return Ok(usage);
+                }
+                entries += 1;
+                let path = dir.join(&md.file_name);
+                if !visible(&path) {
+                    continue;
+                }
+                match md.file_type {
+                    FileType::RegularFile => {
+                        usage.bytes += md.size.unwrap_or_default();
+                        usage.files += 1;
+                    }
+                    FileType::Directory if depth < max_depth => {
+                        queue.push_back((path, depth + 1));
+                    }
+                    FileType::Directory => usage.truncated = true,
+                    FileType::Link => {}
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// The total size of the files under a directory (see
+/// [`OpenFile::disk_usage`])
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Sum of the sizes of the regular files
+    pub bytes: u64,
+    /// Number of regular files
+    pub files: u64,
+    /// Whether the walk was cut short (by depth or by entries)
+    pub truncated: bool,
+}
+
+/// The local file system, as an [`OpenFile`] backend
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFile;
+
+#[async_trait]
+impl OpenFile for LocalFile {
+    #[instrument(err)]
+    async fn open_file(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<Box<dyn TokioFile>> {
+        let file = tokio::fs::File::open(chroot.join(virt_path))
+            .await
+            .context("open file")?;
+        Ok(Box::new(file))
+    }
+
+    async fn read_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        read_metadata(chroot, virt_path).await
+    }
+
+    async fn read_link_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        read_link_metadata(chroot, virt_path).await
+    }
+
+    async fn canonicalize(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<PathBuf> {
+        canonicalize(chroot, virt_path).await
+    }
+
+    async fn list_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadataStream> {
+        list_directory(chroot, virt_path).await
+    }
+
+    async fn count_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+        max: usize,
+    ) -> Result<(usize, bool)> {
+        count_directory(chroot, virt_path, max).await
+    }
+}
+
+/// Cached values of one kind, by real path, with when each was
+/// cached
+type TtlMap<V> = Mutex<HashMap<PathBuf, (Instant, V)>>;
+
+/// An [`OpenFile`] backend that remembers, for a short time (the
+/// TTL), the metadata and canonical paths read through another
+///
+/// It can wrap any backend, and be used wherever one is. Only
+/// successes are remembered. Within the TTL, changes to the storage
+/// may go unnoticed, so keep it to a few seconds.
+///
+/// Canonical paths are also forgotten as soon as the path's own last
+/// modified time (not following links) changes, as when a link is
+/// replaced. Checking costs a single metadata read, which is still
+/// cheaper than resolving every component again (especially where
+/// each is a round trip, as on network file systems).
+#[derive(Debug)]
+pub struct CachedFile {
+    /// Backend to read through
+    inner: Arc<dyn OpenFile>,
+    /// How long to remember things for
+    ttl: Duration,
+    /// Results of [`OpenFile::read_metadata`]
+    metadata: TtlMap<FileMetadata>,
+    /// Results of [`OpenFile::read_link_metadata`]
+    link_metadata: TtlMap<FileMetadata>,
+    /// Results of [`OpenFile::canonicalize`], with the last modified
+    /// time of the path (not followed) when they were
+    canonical: TtlMap<(Option<DateTime>, PathBuf)>,
+}
+
+impl CachedFile {
+    /// Forget everything of a kind once this many are remembered
+    const CAPACITY: usize = 10_000;
+
+    /// Cache the backend for the given TTL
+    pub fn new(inner: Arc<dyn OpenFile>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            metadata: Default::default(),
+            link_metadata: Default::default(),
+            canonical: Default::default(),
+        }
+    }
+
+    /// Look up a value, if not stale
+    fn get<V: Clone>(&self, map: &TtlMap<V>, key: &Path) -> Option<V> {
+        let map = map.lock().unwrap();
+        let (at, value) = map.get(key)?;
+        (at.elapsed() < self.ttl).then(|| value.clone())
+    }
+
+    /// Remember a value
+    fn put<V>(&self, map: &TtlMap<V>, key: PathBuf, value: V) {
+        let mut map = map.lock().unwrap();
+        if map.len() >= Self::CAPACITY {
+            map.clear();
+        }
+        map.insert(key, (Instant::now(), value));
+    }
+}
+
+#[async_trait]
+impl OpenFile for CachedFile {
+    async fn open_file(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<Box<dyn TokioFile>> {
+        self.inner.open_file(chroot, virt_path).await
+    }
+
+    async fn read_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        let key = chroot.join(virt_path);
+        if let Some(md) = self.get(&self.metadata, &key) {
+            return Ok(md);
+        }
+        let md = self.inner.read_metadata(chroot, virt_path).await?;
+        self.put(&self.metadata, key, md.clone());
+        Ok(md)
+    }
+
+    async fn read_link_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        let key = chroot.join(virt_path);
+        if let Some(md) = self.get(&self.link_metadata, &key) {
+            return Ok(md);
+        }
+        let md = self.inner.read_link_metadata(chroot, virt_path).await?;
+        self.put(&self.link_metadata, key, md.clone());
+        Ok(md)
+    }
+
+    async fn canonicalize(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<PathBuf> {
+        let key = chroot.join(virt_path);
+        let modified =
+            match self.inner.read_link_metadata(chroot, virt_path).await {
+                Ok(md) => md.last_modified,
+                // Let the backend tell why (or resolve it anyway)
+                Err(_) => {
+                    return self.inner.canonicalize(chroot, virt_path).await
+                }
+            };
+        match self.get(&self.canonical, &key) {
+            Some((at, path)) if at == modified => return Ok(path),
+            _ => {}
+        }
+        let path = self.inner.canonicalize(chroot, virt_path).await?;
+        self.put(&self.canonical, key, (modified, path.clone()));
+        Ok(path)
+    }
+
+    async fn list_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadataStream> {
+        self.inner.list_directory(chroot, virt_path).await
+    }
+
+    async fn count_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+        max: usize,
+    ) -> Result<(usize, bool)> {
+        self.inner.count_directory(chroot, virt_path, max).await
+    }
+}
+
+/// A search hit: the virtual path of the object and its metadata
+pub type SearchHit = (PathBuf, FileMetadata);
+
+/// Walk a directory tree breadth-first, starting from `virt_path`,
+/// through `backend`, and collect the objects whose file names
+/// satisfy `pred`.
+///
+/// - Descends at most `max_depth` levels below `virt_path`.
+/// - Stops after `max_results` hits. If that happens, the second
+///   element of the returned tuple (`truncated`) is `true`.
+/// - Skips any path rejected by [`bad_path1`] or by `visible` (or
+///   any link whose target is), and any name that isn't UTF-8.
+///   Directories that are skipped aren't descended into either.
+/// - Links are followed only if their targets stay inside the chroot.
+///   The metadata of the target is reported under the link's name.
+///   Links are never descended into, which also rules out loops.
+/// - Entries that fail to be read are skipped silently.
+#[instrument(skip(backend, pred, visible))]
+pub async fn search_directory(
+    backend: &dyn OpenFile,
+    chroot: impl AsRef<RealPath> + Debug + Send + Sync,
+    virt_path: impl AsRef<VirtualPath> + Debug + Send + Sync,
+    pred: impl Fn(&str) -> bool + Send + Sync,
+    visible: impl Fn(&VirtualPath) -> bool + Send + Sync,
+    max_depth: usize,
+    max_results: usize,
+) -> Result<(Vec<SearchHit>, bool)> {
+    let chroot = chroot.as_ref();
+    let mut hits = vec![];
+    let mut queue = VecDeque::from([(virt_path.as_ref().to_owned(), 0)]);
+
+    while let Some((dir, depth)) = queue.pop_front() {
+        let stream = backend.list_directory(chroot, &dir).await;
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            // Only the starting directory must be readable.
+            Err(e) if depth == 0 => return Err(e),
+            Err(e) => {
+                tracing::trace!("skip unreadable directory {dir:?}: {e:?}");
+                continue;
+            }
+        };
+        while let Some(md) = stream.next().await {
+            let Ok(mut md) = md else {
+                continue;
+            };
+            if md.raw_name.is_some() {
+                continue;
+            }
+            let vpath = dir.join(&md.file_name);
+            if bad_path1(&vpath) || !visible(&vpath) {
+                continue;
+            }
+
+            // Follow links, but only within the chroot.
+            if md.file_type == FileType::Link {
+                let Ok(cpath) = backend.canonicalize(chroot, &vpath).await
+                else {
+                    continue;
+                };
+                if !cpath.starts_with(chroot) || bad_path1(&cpath) {
+                    tracing::trace!("link escapes chroot: {vpath:?}");
+                    continue;
+                }
+                if !cpath.strip_prefix(chroot).is_ok_and(&visible) {
+                    continue;
+                }
+                // (Reading through the link keeps the link's name.)
+                let Ok(target) = backend.read_metadata(chroot, &vpath).await
+                else {
+                    continue;
+                };
+                md = target;
+            } else if md.file_type == FileType::Directory && depth < max_depth {
+                queue.push_back((vpath.clone(), depth + 1));
+            }
+
+            if pred(&md.file_name) {
+                if hits.len() >= max_results {
+                    return Ok((hits, true));
+                }
+                hits.push((vpath, md));
+            }
+        }
+    }
+
+    Ok((hits, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn link_loop_is_told_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("b", dir.path().join("a")).unwrap();
+        std::os::unix::fs::symlink("a", dir.path().join("b")).unwrap();
+
+        let e = canonicalize(dir.path(), "a").await.unwrap_err();
+        assert!(is_link_loop(&e), "{e:#}");
+        let status = crate::api::canonicalize_error(e).into_response().status();
+        assert_eq!(status, StatusCode::LOOP_DETECTED);
+    }
+
+    #[tokio::test]
+    async fn missing_is_not_a_link_loop() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let e = canonicalize(dir.path(), "nowhere").await.unwrap_err();
+        assert!(!is_link_loop(&e), "{e:#}");
+        let status = crate::api::canonicalize_error(e).into_response().status();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn search_follows_links_only_within_the_root() {
+        use crate::mem::*;
+
+        let mem = MemFile::default();
+        mem.insert("a/b.txt", MemNode::File(b"hello".to_vec()));
+        mem.insert("a/c/b.txt", MemNode::Link("../b.txt".into()));
+        mem.insert("a/c/d.txt", MemNode::Link("../../../etc/b.txt".into()));
+        mem.insert("b.txt", MemNode::Link("a".into()));
+
+        let (hits, truncated) = search_directory(
+            &mem,
+            Path::new("/srv"),
+            "",
+            |name| name.ends_with(".txt"),
+            |_| true,
+            16,
+            100,
+        )
+        .await
+        .unwrap();
+        assert!(!truncated);
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|(hpath, md)| (hpath.to_str().unwrap(), md.file_type))
+            .collect();
+        assert_eq!(
+            hits,
+            [
+                ("b.txt", FileType::Directory),
+                ("a/b.txt", FileType::RegularFile),
+                ("a/c/b.txt", FileType::RegularFile),
+            ]
+        );
+    }
 }
@@ -1,54 +1,636 @@
 //! File Lister --- list files in a directory (don't download)
 
-use std::{path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
+use axum::{middleware::from_fn_with_state, Router};
 use tokio::join;
-use tower_http::trace::TraceLayer;
+use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
 
 mod api;
+mod archive;
 mod basicfe;
 mod fs;
+#[cfg(test)]
+mod mem;
 mod prim;
+#[cfg(feature = "s3")]
+mod s3;
+mod sign;
 mod thumb;
 
-#[tokio::main]
-async fn main() {
-    // Init logging
-    tracing_subscriber::fmt::init();
-    let tracer = TraceLayer::new_for_http();
+/// Read a positive number of threads from an environment variable, if
+/// set
+fn threads_from_env(var: &str) -> Option<usize> {
+    let threads = std::env::var(var).ok()?;
+    let threads = threads.parse().ok().filter(|&threads| threads > 0);
+    Some(threads.unwrap_or_else(|| {
+        panic!("expect {var} to be a positive number of threads")
+    }))
+}
+
+/// Read a flag (`1` or `0`) from an environment variable, if set
+fn flag_from_env(var: &str) -> Option<bool> {
+    let flag = std::env::var(var).ok()?;
+    match flag.as_str() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => panic!("expect {var} to be 1 or 0"),
+    }
+}
+
+/// Who may call a service
+#[derive(Debug, Clone, Copy)]
+enum Exposure {
+    /// Anyone who may sign in (see GAGAGA_AUTH_USER)
+    Public,
+    /// Anyone who may sign in, or who has a signed URL (see
+    /// GAGAGA_URL_SECRET)
+    Signed,
+    /// Only the basic front-end (see GAGAGA_INTERNAL_SECRET)
+    Internal,
+}
+
+/// The middleware that all the services (but the health checks) are
+/// served with
+#[derive(Debug, Clone)]
+struct Stack {
+    /// What's hidden
+    visibility: api::Visibility,
+    /// Credentials to sign in with, if any
+    auth: Option<Arc<api::BasicAuth>>,
+    /// Secret of the front-end, if any (for [`Exposure::Internal`])
+    internal_only: Option<Arc<[u8]>>,
+    /// Secret of signed URLs, if any (for [`Exposure::Signed`])
+    url_secret: Option<Arc<[u8]>>,
+    /// Requests in flight from each client
+    clients: api::ClientLimiter,
+    /// Where to log requests, if anywhere
+    access_log: Option<api::AccessLog>,
+    /// Which proxies to believe about clients' addresses
+    proxies: Arc<api::ProxyPolicy>,
+    /// How long to wait for a response to start, if not forever
+    timeout: Option<Duration>,
+}
+
+impl Stack {
+    /// Wrap a service in the middleware, with the security headers
+    fn wrap(
+        &self,
+        router: Router,
+        headers: &Arc<api::SecurityHeaders>,
+        exposure: Exposure,
+    ) -> Router {
+        let mut router = router
+            .layer(from_fn_with_state(
+                self.visibility.clone(),
+                api::mw_set_visibility,
+            ))
+            .layer(from_fn_with_state(self.auth.clone(), api::mw_basic_auth));
+        if let Exposure::Internal = exposure {
+            router = router.layer(from_fn_with_state(
+                self.internal_only.clone(),
+                api::mw_internal_only,
+            ));
+        }
+        router = router
+            .layer(from_fn_with_state(
+                self.clients.clone(),
+                api::mw_limit_per_client,
+            ))
+            .layer(from_fn_with_state(
+                self.access_log.clone(),
+                api::mw_access_log,
+            ))
+            .layer(from_fn_with_state(self.proxies.clone(), api::mw_client_ip))
+            .layer(from_fn_with_state(
+                headers.clone(),
+                api::mw_security_headers,
+            ));
+        if let Exposure::Signed = exposure {
+            router = router.layer(from_fn_with_state(
+                self.url_secret.clone(),
+                api::mw_signed_url,
+            ));
+        }
+        if let Some(timeout) = self.timeout {
+            router = router.layer(TimeoutLayer::new(timeout));
+        }
+        router.layer(TraceLayer::new_for_http())
+    }
+}
+
+/// Serve at the port (of the loopback address)
+async fn serve_at(port: u16, router: Router) {
+    axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], port)))
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap()
+}
+
+fn main() {
+    // Handle requests on GAGAGA_WORKER_THREADS threads (one per CPU
+    // by default), and block (on files, mostly) on at most
+    // GAGAGA_BLOCKING_THREADS more (512 by default), if set. Images
+    // are decoded on threads of their own (see GAGAGA_CPU_THREADS).
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = threads_from_env("GAGAGA_WORKER_THREADS") {
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = threads_from_env("GAGAGA_BLOCKING_THREADS") {
+        runtime.max_blocking_threads(threads);
+    }
+    runtime
+        .build()
+        .expect("expect to build the runtime")
+        .block_on(serve());
+}
+
+async fn serve() {
+    // `gagaga sign <path> <seconds>`: print a URL path that grants
+    // access to the path for that many seconds, and exit.
+    let args: Vec<_> = std::env::args().collect();
+    if let [_, cmd, path, secs] = &args[..] {
+        if cmd == "sign" {
+            let secret = std::env::var("GAGAGA_URL_SECRET")
+                .expect("expect GAGAGA_URL_SECRET to be set");
+            let secs: i64 = secs.parse().expect("expect seconds");
+            let exp = prim::DateTime::now().sgnunixsec() + secs;
+            println!("{}", sign::sign_url(path, exp, secret.as_bytes()));
+            return;
+        }
+    }
+
+    // Init logging, in JSON if GAGAGA_LOG_FORMAT=json
+    if std::env::var("GAGAGA_LOG_FORMAT").is_ok_and(|f| f == "json") {
+        tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+
+    // Init metrics
+    let metrics = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .set_buckets(&[
+            0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ])
+        .expect("set histogram buckets")
+        .install_recorder()
+        .expect("install the metrics recorder");
+
+    // Give up on requests that take longer than GAGAGA_TIMEOUT seconds
+    // (30 by default) to start responding (408 Request Timeout), such
+    // as when the file system is stuck. Responses already being
+    // streamed are not affected, and neither are uploads.
+    let timeout = std::env::var("GAGAGA_TIMEOUT")
+        .map(|secs| {
+            secs.parse()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .expect("expect GAGAGA_TIMEOUT to be a positive number")
+        })
+        .unwrap_or(30);
+    let timeout = Duration::from_secs(timeout);
+
+    // Password-protect everything but the health checks, if
+    // GAGAGA_AUTH_USER and GAGAGA_AUTH_HASH are set
+    let auth = api::BasicAuth::from_env().map(Arc::new);
+
+    // Read (and send) files GAGAGA_CHUNK_SIZE bytes at a time, if set,
+    // for downloads and thumbnails
+    let chunk_size = std::env::var("GAGAGA_CHUNK_SIZE")
+        .ok()
+        .map(|size| {
+            size.parse::<usize>()
+                .ok()
+                .filter(|&size| size > 0)
+                .expect("expect GAGAGA_CHUNK_SIZE to be a positive number")
+        })
+        .unwrap_or(api::DEFAULT_CHUNK_SIZE);
+    // Decode, resample and encode images on a pool of
+    // GAGAGA_CPU_THREADS threads (one per physical core by default),
+    // if set, shared by all the services that do. Each job costs about
+    // a core while it runs, so more threads than cores only make each
+    // thumbnail slower, and fewer leave cores for everything else.
+    let cpu_pool = thumb::CpuPool::new(
+        threads_from_env("GAGAGA_CPU_THREADS")
+            .unwrap_or_else(num_cpus::get_physical),
+    );
+    // Refuse to decode images of more than GAGAGA_MAX_PIXELS pixels,
+    // if set (they are thumbnailed as a broken image icon instead)
+    let mut decode = thumb::DecodeLimits::default();
+    if let Ok(max_pixels) = std::env::var("GAGAGA_MAX_PIXELS") {
+        decode.max_pixels = max_pixels
+            .parse()
+            .expect("expect GAGAGA_MAX_PIXELS to be a number");
+    }
+    let thumb_policy = api::ThumbPolicy {
+        chunk_size,
+        decode,
+        pool: cpu_pool,
+        ..Default::default()
+    };
+
+    // Behind reverse proxies, find the real client's address in the
+    // header named by GAGAGA_CLIENT_IP_HEADER (X-Forwarded-For by
+    // default, X-Real-IP, or Forwarded), but only on connections from
+    // the comma-separated CIDRs in GAGAGA_TRUSTED_PROXIES (such as
+    // `10.0.0.0/8,::1`), if set
+    let proxies = std::env::var("GAGAGA_TRUSTED_PROXIES").unwrap_or_default();
+    let proxies = api::ProxyPolicy {
+        trusted: proxies
+            .split(',')
+            .filter(|cidr| !cidr.is_empty())
+            .map(|cidr| cidr.trim().parse())
+            .collect::<Result<_, _>>()
+            .expect("expect GAGAGA_TRUSTED_PROXIES to be a list of CIDRs"),
+        header: std::env::var("GAGAGA_CLIENT_IP_HEADER")
+            .map(|header| header.parse())
+            .unwrap_or(Ok(Default::default()))
+            .expect("expect a known GAGAGA_CLIENT_IP_HEADER"),
+    };
+    let proxies = Arc::new(proxies);
+
+    // Limit how many requests each client may have in flight at once,
+    // to GAGAGA_MAX_IN_FLIGHT_PER_IP, if set, but not those from
+    // loopback addresses if GAGAGA_EXEMPT_LOOPBACK=1
+    let mut client_policy = api::ClientPolicy::default();
+    if let Ok(max) = std::env::var("GAGAGA_MAX_IN_FLIGHT_PER_IP") {
+        client_policy.max_in_flight_per_ip = max
+            .parse()
+            .expect("expect GAGAGA_MAX_IN_FLIGHT_PER_IP to be a number");
+    }
+    if let Some(exempt) = flag_from_env("GAGAGA_EXEMPT_LOOPBACK") {
+        client_policy.exempt_loopback = exempt;
+    }
+    if proxies.trusted.is_empty() {
+        tracing::warn!(
+            "no GAGAGA_TRUSTED_PROXIES, so clients behind a proxy share \
+            its address, and one limit of {} requests in flight",
+            client_policy.max_in_flight_per_ip
+        );
+    }
+    let clients = api::ClientLimiter::new(client_policy);
+
+    // Log every request in the Combined Log Format (for traffic
+    // analysis, apart from the tracing), if GAGAGA_ACCESS_LOG is set,
+    // to that file, or to standard output if it's `-`
+    let access_log = std::env::var("GAGAGA_ACCESS_LOG")
+        .ok()
+        .map(|path| match path.as_str() {
+            "-" => Ok(api::AccessLog::stdout()),
+            path => api::AccessLog::open(path),
+        })
+        .transpose()
+        .expect("expect GAGAGA_ACCESS_LOG to be writable");
+
+    // Allow signed URLs to the download server, if GAGAGA_URL_SECRET
+    // is set
+    let url_secret: Option<Arc<[u8]>> = std::env::var("GAGAGA_URL_SECRET")
+        .ok()
+        .map(|secret| secret.into_bytes().into());
+
+    // Accept requests for metadata (the list, streaming list, watch,
+    // stat, count, search, disk usage, dimensions and BlurHash
+    // services) only from the front-end, which sends
+    // GAGAGA_INTERNAL_SECRET with them, if set. (The pages, and the
+    // thumbnails and files that browsers fetch directly, can't be kept
+    // internal like this; see GAGAGA_URL_SECRET for downloads.)
+    let internal_secret = std::env::var("GAGAGA_INTERNAL_SECRET").ok();
+    let internal_only: Option<Arc<[u8]>> = internal_secret
+        .clone()
+        .map(|secret| secret.into_bytes().into());
+
+    // Hide dotfiles (such as `.git` or `.env`) from all services, and
+    // whatever matches the comma-separated globs in GAGAGA_IGNORE
+    // (such as `*.tmp,Thumbs.db,node_modules`), if set
+    let ignore = std::env::var("GAGAGA_IGNORE").unwrap_or_default();
+    let visibility = api::VisibilityPolicy::default()
+        .ignoring(ignore.split(',').filter(|glob| !glob.is_empty()))
+        .expect("expect GAGAGA_IGNORE to be a list of globs");
+    let visibility = api::Visibility::new(visibility);
+
+    // Security headers (CSP and others) for the front-end and the
+    // autoindex pages, whose Content-Security-Policy may be replaced
+    // with GAGAGA_CSP, and stricter ones for files and listings
+    let mut page_headers = api::SecurityHeaders::default();
+    if let Ok(csp) = std::env::var("GAGAGA_CSP") {
+        page_headers.content_security_policy = Some(csp);
+    }
+    let page_headers = Arc::new(page_headers);
+    let file_headers = Arc::new(api::SecurityHeaders::for_files());
+
+    // Let web apps on the comma-separated origins in
+    // GAGAGA_CORS_ORIGINS (such as `https://app.example.com`, or `*`)
+    // call the list and thumbnail APIs, and open WebSockets to the
+    // watch API (which turns away all other origins), if set. (The
+    // list and watch APIs are internal if GAGAGA_INTERNAL_SECRET is
+    // set, though.)
+    let cors = api::CorsPolicy {
+        allowed_origins: std::env::var("GAGAGA_CORS_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_string)
+            .collect(),
+        ..Default::default()
+    };
 
     let chroot = PathBuf::from("/");
     let chroot = Arc::new(chroot);
 
+    // Storage backend for listing, searching, archiving and
+    // thumbnailing: the local file system, or, if built with the `s3`
+    // feature and GAGAGA_S3_BUCKET is set, that bucket. Some services
+    // ignore it and always use the local file system: downloads
+    // (served by ServeDir, directory archives included) and the APIs
+    // that change files (move, delete, upload, and make directory),
+    // and the watch API, which watches local directories.
+    #[cfg(feature = "s3")]
+    let backend: Arc<dyn fs::OpenFile> = match std::env::var("GAGAGA_S3_BUCKET")
+    {
+        Ok(bucket) => Arc::new(s3::S3File::from_env(bucket).await),
+        Err(_) => Arc::new(fs::LocalFile),
+    };
+    #[cfg(not(feature = "s3"))]
+    let backend: Arc<dyn fs::OpenFile> = Arc::new(fs::LocalFile);
+    // Remember metadata and canonical paths for GAGAGA_CACHE_TTL
+    // milliseconds, if set, or else a couple of seconds. Zero turns the
+    // cache off, so that every request goes to the storage.
+    let cache_ttl = match std::env::var("GAGAGA_CACHE_TTL") {
+        Ok(ms) => Duration::from_millis(
+            ms.parse()
+                .expect("expect GAGAGA_CACHE_TTL to be a number (ms)"),
+        ),
+        Err(_) => Duration::from_secs(2),
+    };
+    let backend: Arc<dyn fs::OpenFile> = if cache_ttl.is_zero() {
+        backend
+    } else {
+        Arc::new(fs::CachedFile::new(backend, cache_ttl))
+    };
+
+    // What every service but the health checks goes through
+    let stack = Stack {
+        visibility,
+        auth,
+        internal_only,
+        url_secret,
+        clients,
+        access_log,
+        proxies,
+        timeout: Some(timeout),
+    };
+
+    // Which services may be called by whom (see Exposure): the pages,
+    // thumbnails and files by anyone who may sign in, and the metadata
+    // only by the front-end
+    use Exposure::*;
+
     // Bind basicfe (front-end) at 3000
     let basicfe_config = basicfe::BasicFrontend {
         download_base_url: "http://127.0.0.1:2997".to_string(),
         list_base_url: "http://127.0.0.1:2999".to_string(),
+        thumb_base_url: "http://127.0.0.1:2998".to_string(),
+        connect_timeout: Duration::from_secs(5),
+        // Less than the server's own timeout, so that a hung back end
+        // gets a proper error page.
+        request_timeout: timeout * 5 / 6,
+        pool_max_idle_per_host: 32,
+        tcp_keepalive: Duration::from_secs(60),
+        display: basicfe::DisplayConfig::default(),
+        internal_secret: internal_secret.clone(),
     };
-    let basicfe =
-        basicfe::build_api_basicfe(&basicfe_config).layer(tracer.clone());
-    let basicfe = axum::Server::bind(&"127.0.0.1:3000".parse().unwrap())
-        .serve(basicfe.into_make_service());
-    let basicfe = async move { basicfe.await.unwrap() };
+    let basicfe = basicfe::build_api_basicfe(&basicfe_config)
+        .layer(from_fn_with_state(api::ALLOW_GET, api::mw_options));
+    let basicfe = serve_at(3000, stack.wrap(basicfe, &page_headers, Public));
+
+    // How to list directories: at most GAGAGA_MAX_ENTRIES entries,
+    // following links unless GAGAGA_FOLLOW_SYMLINKS=0, with names that
+    // aren't UTF-8 if GAGAGA_LOSSY_NAMES=1, and following at most
+    // GAGAGA_LIST_CONCURRENCY links at once, if set
+    let mut list_policy = api::ListPolicy::default();
+    if let Ok(max_entries) = std::env::var("GAGAGA_MAX_ENTRIES") {
+        list_policy.max_entries = max_entries
+            .parse()
+            .ok()
+            .filter(|&max_entries| max_entries > 0)
+            .expect("expect GAGAGA_MAX_ENTRIES to be a positive number");
+    }
+    if let Some(follow) = flag_from_env("GAGAGA_FOLLOW_SYMLINKS") {
+        list_policy.follow_symlinks = follow;
+    }
+    if let Some(lossy) = flag_from_env("GAGAGA_LOSSY_NAMES") {
+        list_policy.lossy_names = lossy;
+    }
+    if let Ok(concurrency) = std::env::var("GAGAGA_LIST_CONCURRENCY") {
+        list_policy.concurrency = concurrency
+            .parse()
+            .ok()
+            .filter(|&concurrency| concurrency > 0)
+            .expect("expect GAGAGA_LIST_CONCURRENCY to be a positive number");
+    }
 
     // Bind list at 2999
-    let list = api::build_list_api(chroot.clone()).layer(tracer.clone());
-    let list = axum::Server::bind(&"127.0.0.1:2999".parse().unwrap())
-        .serve(list.into_make_service());
-    let list = async move { list.await.unwrap() };
+    let list = api::build_list_api(
+        chroot.clone(),
+        backend.clone(),
+        list_policy.clone(),
+        cors.clone(),
+    );
+    let list = serve_at(2999, stack.wrap(list, &file_headers, Internal));
 
     // Bind thumb at 2998
-    let thumb = api::build_thumb_api(chroot.clone()).layer(tracer.clone());
-    let thumb = axum::Server::bind(&"127.0.0.1:2998".parse().unwrap())
-        .serve(thumb.into_make_service());
-    let thumb = async move { thumb.await.unwrap() };
+    let thumb = api::build_thumb_api(
+        chroot.clone(),
+        backend.clone(),
+        thumb_policy.clone(),
+        cors.clone(),
+    );
+    let thumb = serve_at(2998, stack.wrap(thumb, &file_headers, Public));
+
+    // Download server at 2997, serving files with the extensions in
+    // GAGAGA_CONTENT_TYPES (such as `log=text/plain,heic=image/heic`),
+    // if set, as those types
+    let content_types = std::env::var("GAGAGA_CONTENT_TYPES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (ext, content_type) = pair
+                .split_once('=')
+                .expect("expect GAGAGA_CONTENT_TYPES to be ext=type pairs");
+            (ext.trim().to_string(), content_type.trim().to_string())
+        })
+        .collect();
+    let download_policy = api::DownloadPolicy {
+        content_types,
+        chunk_size,
+        ..Default::default()
+    };
+    let download = api::build_download_api(chroot.clone(), download_policy);
+    let download = stack.wrap(download, &file_headers, Signed);
+
+    // Archives of selected files (POST /dl/archive, a JSON array of
+    // paths) there too, but not by signed URLs. (Only the archives are
+    // found at /dl/archive then, not a file by that name.)
+    let archive_policy = api::DownloadPolicy {
+        chunk_size,
+        ..Default::default()
+    };
+    let archive =
+        api::build_archive_api(chroot.clone(), backend.clone(), archive_policy);
+    let archive = Router::new().nest("/dl/archive", archive);
+    let mut download =
+        download.merge(stack.wrap(archive, &file_headers, Public));
+
+    // Search server at 2996
+    let search = api::build_search_api(chroot.clone(), backend.clone());
+    let search = serve_at(2996, stack.wrap(search, &file_headers, Internal));
+
+    // Streaming list (NDJSON) at 2995
+    let list_stream = api::build_list_stream_api(
+        chroot.clone(),
+        backend.clone(),
+        list_policy.clone(),
+    );
+    let list_stream =
+        serve_at(2995, stack.wrap(list_stream, &file_headers, Internal));
+
+    // Watch (changes to a directory, over a WebSocket, at /watch) at
+    // 2981, at most GAGAGA_MAX_WATCHERS (or 256) directories at once
+    let max_watchers = match std::env::var("GAGAGA_MAX_WATCHERS") {
+        Ok(max) => max
+            .parse()
+            .expect("expect GAGAGA_MAX_WATCHERS to be a number"),
+        Err(_) => 256,
+    };
+    let watch = api::build_watch_api(
+        chroot.clone(),
+        list_policy.clone(),
+        cors,
+        max_watchers,
+    );
+    let watch = serve_at(2981, stack.wrap(watch, &file_headers, Internal));
+
+    // Stat (metadata of a single object) at 2994
+    let stat = api::build_stat_api(chroot.clone(), backend.clone());
+    let stat = serve_at(2994, stack.wrap(stat, &file_headers, Internal));
+
+    // Count (entries of a directory) at 2993
+    let count = api::build_count_api(chroot.clone(), backend.clone());
+    let count = serve_at(2993, stack.wrap(count, &file_headers, Internal));
+
+    // Autoindex (plain HTML listing) at 2991
+    let autoindex =
+        api::build_autoindex_api(chroot.clone(), backend.clone(), list_policy);
+    let autoindex =
+        serve_at(2991, stack.wrap(autoindex, &page_headers, Public));
+
+    // Dimensions (width and height of images) at 2990
+    let dimensions = api::build_dimensions_api(chroot.clone(), backend.clone());
+    let dimensions =
+        serve_at(2990, stack.wrap(dimensions, &file_headers, Internal));
+
+    // BlurHash (placeholders for images) at 2989
+    let blurhash = api::build_blurhash_api(
+        chroot.clone(),
+        backend.clone(),
+        thumb_policy.clone(),
+    );
+    let blurhash =
+        serve_at(2989, stack.wrap(blurhash, &file_headers, Internal));
+
+    // Bulk thumbnails (POST a JSON array of paths) at 2988
+    let thumbs =
+        api::build_thumbs_api(chroot.clone(), backend.clone(), thumb_policy);
+    let thumbs = serve_at(2988, stack.wrap(thumbs, &file_headers, Public));
+
+    // Disk usage (recursive directory sizes) at 2987
+    let du = api::build_du_api(chroot.clone(), backend.clone());
+    let du = serve_at(2987, stack.wrap(du, &file_headers, Internal));
+
+    // Files may be moved, uploaded and deleted, and directories made,
+    // only if GAGAGA_WRITABLE=1 (the server is read-only otherwise).
+    // Then files under /mv, /file and /mkdir can't be downloaded, since
+    // those paths are taken.
+    let writable = std::env::var("GAGAGA_WRITABLE").is_ok_and(|w| w == "1");
+
+    // Moving and renaming (POST /mv, {"from": .., "to": ..}) on the
+    // download server
+    if writable {
+        let mv = api::build_mv_api(chroot.clone(), backend.clone());
+        let mv = Router::new().nest("/mv", mv);
+        download = download.merge(stack.wrap(mv, &file_headers, Public));
+    }
+
+    // Uploading (PUT /file/path, with ?overwrite=1 to replace a file)
+    // and deleting (DELETE /file/path, with ?recursive=1 for whole
+    // directories) on the download server. Uploads are of at most
+    // GAGAGA_MAX_UPLOAD bytes, if set, and may take as long as they
+    // need (rather than be cut short by the timeout).
+    if writable {
+        let upload_policy = match std::env::var("GAGAGA_MAX_UPLOAD") {
+            Ok(max_size) => api::UploadPolicy {
+                max_size: max_size
+                    .parse()
+                    .expect("expect GAGAGA_MAX_UPLOAD to be a number"),
+            },
+            Err(_) => api::UploadPolicy::default(),
+        };
+        let upload = api::build_upload_api(
+            chroot.clone(),
+            backend.clone(),
+            upload_policy,
+        );
+        let delete = api::build_delete_api(chroot.clone(), backend.clone())
+            .layer(TimeoutLayer::new(timeout));
+        let files = upload
+            .merge(delete)
+            .layer(from_fn_with_state("PUT, DELETE, OPTIONS", api::mw_options));
+        let files = Router::new().nest("/file", files);
+        let untimed = Stack {
+            timeout: None,
+            ..stack.clone()
+        };
+        download = download.merge(untimed.wrap(files, &file_headers, Public));
+    }
+
+    // Making directories (POST /mkdir/path, with ?parents=1 to make
+    // missing parents too) on the download server
+    if writable {
+        let mkdir = api::build_mkdir_api(chroot.clone(), backend.clone());
+        let mkdir = Router::new().nest("/mkdir", mkdir);
+        download = download.merge(stack.wrap(mkdir, &file_headers, Public));
+    }
 
-    // Download server at 2997
-    let download = api::build_download_api(chroot).layer(tracer);
-    let download = axum::Server::bind(&"127.0.0.1:2997".parse().unwrap())
-        .serve(download.into_make_service());
-    let download = async move { download.await.unwrap() };
+    // Health checks (/healthz and /readyz) and metrics (/metrics) at 2992
+    let health = api::build_health_api(chroot, backend, metrics)
+        .layer(TimeoutLayer::new(timeout))
+        .layer(TraceLayer::new_for_http());
+    let health = serve_at(2992, health);
 
     // Go
-    join!(basicfe, list, thumb, download);
+    let download = serve_at(2997, download);
+    join!(
+        basicfe,
+        list,
+        thumb,
+        download,
+        search,
+        list_stream,
+        watch,
+        stat,
+        count,
+        autoindex,
+        dimensions,
+        blurhash,
+        thumbs,
+        du,
+        health
+    );
 }
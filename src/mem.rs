@@ -0,0 +1,332 @@
+//! In-memory storage backend, for tests
+//!
+//! Only built for tests.
+//!
+//! The objects live in a map from virtual paths (relative to the root)
+//! to nodes, so tests can set up a tree, including links and last
+//! modified times, without touching the disk, and get the same
+//! answers every time. The chroot is only joined onto canonical paths,
+//! so that they look as real as those of the local file system.
+
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Component, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::bail;
+use async_trait::async_trait;
+
+use crate::{fs::*, prim::*};
+
+/// Most links to follow while canonicalizing a path, as on Linux
+const MAX_LINKS: usize = 40;
+
+/// An object held by [`MemFile`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemNode {
+    /// A regular file, with its contents
+    File(Vec<u8>),
+    /// A directory
+    Directory,
+    /// A link to a path relative to the link's directory, or, if the
+    /// path begins with `/`, to the root
+    Link(PathBuf),
+}
+
+/// A file system held in memory, as an [`OpenFile`] backend
+///
+/// It starts out with only the root directory. Objects are last
+/// modified at the UNIX epoch unless set otherwise.
+#[derive(Debug)]
+pub struct MemFile {
+    /// The objects, with their last modified times, by virtual path
+    /// (the root being the empty path)
+    nodes: Mutex<BTreeMap<PathBuf, (MemNode, DateTime)>>,
+}
+
+impl Default for MemFile {
+    fn default() -> Self {
+        let root = (MemNode::Directory, UNIX_EPOCH.into());
+        Self {
+            nodes: Mutex::new(BTreeMap::from([(PathBuf::new(), root)])),
+        }
+    }
+}
+
+/// Turn a virtual path into a key of [`MemFile`], leaving out the
+/// root and `.`
+fn key(virt_path: &VirtualPath) -> PathBuf {
+    virt_path
+        .components()
+        .filter(|c| !matches!(c, Component::RootDir | Component::CurDir))
+        .collect()
+}
+
+impl MemFile {
+    /// Put the node at the virtual path (replacing what's there, if
+    /// anything), making the missing directories above it
+    pub fn insert(&self, virt_path: impl AsRef<VirtualPath>, node: MemNode) {
+        let key = key(virt_path.as_ref());
+        let mut nodes = self.nodes.lock().unwrap();
+        for dir in key.ancestors().skip(1) {
+            nodes
+                .entry(dir.to_owned())
+                .or_insert((MemNode::Directory, UNIX_EPOCH.into()));
+        }
+        nodes.insert(key, (node, UNIX_EPOCH.into()));
+    }
+
+    /// Set the last modified time of the object at the virtual path
+    /// (not following links)
+    ///
+    /// Panics if there is none.
+    pub fn set_last_modified(
+        &self,
+        virt_path: impl AsRef<VirtualPath>,
+        last_modified: DateTime,
+    ) {
+        let key = key(virt_path.as_ref());
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(&key).expect("expect the object to exist");
+        node.1 = last_modified;
+    }
+
+    /// Follow the links along a virtual path, and return the key of
+    /// the object it leads to
+    ///
+    /// A path that leads above the root, or through a file, isn't
+    /// found. Too many links (such as a loop) fail as they would on
+    /// Unix (see [`is_link_loop`]).
+    fn resolve(&self, virt_path: &VirtualPath) -> Result<PathBuf> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut done = PathBuf::new();
+        let mut todo: Vec<_> = key(virt_path)
+            .components()
+            .rev()
+            .map(|c| c.as_os_str().to_owned())
+            .collect();
+        let mut links = 0;
+        while let Some(name) = todo.pop() {
+            if name == ".." {
+                if !done.pop() {
+                    bail!("leads above the root: {virt_path:?}");
+                }
+                continue;
+            }
+            let next = done.join(&name);
+            match nodes.get(&next) {
+                None => bail!("not found: {next:?}"),
+                Some((MemNode::Link(target), _)) => {
+                    links += 1;
+                    if links > MAX_LINKS {
+                        #[cfg(unix)]
+                        let e = std::io::Error::from_raw_os_error(libc::ELOOP);
+                        #[cfg(not(unix))]
+                        let e = std::io::Error::other("too many links");
+                        return Err(e)
+                            .context(format!("resolve {virt_path:?}"));
+                    }
+                    if target.has_root() {
+                        done = PathBuf::new();
+                    }
+                    todo.extend(
+                        key(target)
+                            .components()
+                            .rev()
+                            .map(|c| c.as_os_str().to_owned()),
+                    );
+                }
+                Some((MemNode::File(_), _)) if !todo.is_empty() => {
+                    bail!("not a directory: {next:?}")
+                }
+                Some(_) => done = next,
+            }
+        }
+        Ok(done)
+    }
+
+    /// Describe the node at the key, by the name
+    fn metadata(
+        &self,
+        key: &VirtualPath,
+        name: String,
+    ) -> Result<FileMetadata> {
+        let nodes = self.nodes.lock().unwrap();
+        let (node, last_modified) = nodes
+            .get(key)
+            .with_context(|| format!("not found: {key:?}"))?;
+        Ok(node_metadata(node, *last_modified, name))
+    }
+}
+
+/// Describe a node as [`FileMetadata`]
+fn node_metadata(
+    node: &MemNode,
+    last_modified: DateTime,
+    file_name: String,
+) -> FileMetadata {
+    let (file_type, size) = match node {
+        MemNode::File(bytes) => {
+            (FileType::RegularFile, Some(bytes.len() as u64))
+        }
+        MemNode::Directory => (FileType::Directory, None),
+        MemNode::Link(_) => (FileType::Link, None),
+    };
+    FileMetadata {
+        file_type,
+        file_name,
+        size,
+        last_modified: Some(last_modified),
+        mode: None,
+        created: None,
+        accessed: None,
+        raw_name: None,
+    }
+}
+
+/// The file name of a virtual path, or nothing for the root
+fn name_of(virt_path: &VirtualPath) -> String {
+    virt_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl OpenFile for MemFile {
+    async fn open_file(
+        &self,
+        _chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<Box<dyn TokioFile>> {
+        let key = self.resolve(virt_path)?;
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&key) {
+            Some((MemNode::File(bytes), _)) => {
+                Ok(Box::new(Cursor::new(bytes.clone())))
+            }
+            _ => bail!("not a file: {virt_path:?}"),
+        }
+    }
+
+    async fn read_metadata(
+        &self,
+        _chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        let key = self.resolve(virt_path)?;
+        self.metadata(&key, name_of(virt_path))
+    }
+
+    async fn read_link_metadata(
+        &self,
+        _chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        // Follow the links above the object, but not the object itself.
+        let key = key(virt_path);
+        let key = match (key.parent(), key.file_name()) {
+            (Some(parent), Some(name)) => self.resolve(parent)?.join(name),
+            _ => key,
+        };
+        self.metadata(&key, name_of(virt_path))
+    }
+
+    async fn canonicalize(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<PathBuf> {
+        Ok(chroot.join(self.resolve(virt_path)?))
+    }
+
+    async fn list_directory(
+        &self,
+        _chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadataStream> {
+        let dir = self.resolve(virt_path)?;
+        let nodes = self.nodes.lock().unwrap();
+        if !matches!(nodes.get(&dir), Some((MemNode::Directory, _))) {
+            bail!("not a directory: {virt_path:?}");
+        }
+        // (In order of name, since the map is sorted.)
+        let entries: Vec<_> = nodes
+            .iter()
+            .filter(|(key, _)| key.parent() == Some(&*dir))
+            .map(|(key, (node, last_modified))| {
+                Ok(node_metadata(node, *last_modified, name_of(key)))
+            })
+            .collect();
+        Ok(Box::pin(tokio_stream::iter(entries)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    /// A tree with a file, a directory, and links in and out of it
+    fn tree() -> MemFile {
+        let mem = MemFile::default();
+        mem.insert("/a/b.txt", MemNode::File(b"hello".to_vec()));
+        mem.insert("/c", MemNode::Link("a".into()));
+        mem.insert("/a/d", MemNode::Link("../a/b.txt".into()));
+        mem.insert("/a/e", MemNode::Link("/c/d".into()));
+        mem.insert("/a/out", MemNode::Link("../..".into()));
+        mem.insert("/x", MemNode::Link("y".into()));
+        mem.insert("/y", MemNode::Link("x".into()));
+        mem
+    }
+
+    #[tokio::test]
+    async fn links_are_resolved() {
+        let (mem, chroot) = (tree(), Path::new("/srv"));
+        for vpath in ["a/b.txt", "/c/b.txt", "c/d", "a/e", "/c/e"] {
+            let cpath = mem.canonicalize(chroot, vpath.as_ref()).await.unwrap();
+            assert_eq!(cpath, Path::new("/srv/a/b.txt"), "{vpath}");
+        }
+        assert!(mem.canonicalize(chroot, "a/out".as_ref()).await.is_err());
+        assert!(mem
+            .canonicalize(chroot, "a/b.txt/f".as_ref())
+            .await
+            .is_err());
+        let e = mem.canonicalize(chroot, "x".as_ref()).await.unwrap_err();
+        assert!(is_link_loop(&e), "{e:#}");
+    }
+
+    #[tokio::test]
+    async fn links_are_described_as_links_unless_followed() {
+        let (mem, chroot) = (tree(), Path::new("/srv"));
+        mem.set_last_modified("a/b.txt", DateTime::now());
+
+        let md = mem.read_metadata(chroot, "c/d".as_ref()).await.unwrap();
+        assert_eq!(md.file_type, FileType::RegularFile);
+        assert_eq!((md.file_name.as_str(), md.size), ("d", Some(5)));
+        assert_ne!(md.last_modified, Some(UNIX_EPOCH.into()));
+        let md = mem.read_link_metadata(chroot, "c/d".as_ref()).await;
+        assert_eq!(md.unwrap().file_type, FileType::Link);
+
+        let names: Vec<_> = mem
+            .list_directory(chroot, "c".as_ref())
+            .await
+            .unwrap()
+            .map(|md| md.unwrap().file_name)
+            .collect()
+            .await;
+        assert_eq!(names, ["b.txt", "d", "e", "out"]);
+
+        let mut buf = String::new();
+        let mut file = mem.open_file(chroot, "a/e".as_ref()).await.unwrap();
+        file.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello");
+    }
+}
@@ -3,6 +3,8 @@
 //! - Error and Result
 //! - Time handling
 //! - Logging and [`macro@instrument`] macro
+//! - Percent-encoding URL paths
+//! - Human readable sizes
 
 pub use anyhow::{anyhow, Context};
 pub use tracing::instrument;
@@ -10,6 +12,7 @@ pub use tracing::instrument;
 use std::{cmp::Ordering, fmt::Debug, time::SystemTime};
 
 use httpdate::{fmt_http_date, parse_http_date};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use time::OffsetDateTime;
 
 /// Unified Error Type
@@ -80,6 +83,20 @@ impl DateTime {
             .unwrap()
     }
 
+    /// As used in access logs (Common Log Format), such as
+    /// `10/Oct/2000:13:55:36 +0000`
+    pub fn clf(&self) -> String {
+        use time::macros::format_description;
+
+        self.0
+            .format(format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] \
+                [offset_hour sign:mandatory][offset_minute]"
+            ))
+            .context("formatting date for access log")
+            .unwrap()
+    }
+
     /// As used in Last-Modified
     pub fn http(&self) -> String {
         fmt_http_date(self.0.into())
@@ -107,6 +124,11 @@ impl DateTime {
     pub fn sgnunixsec(&self) -> i64 {
         self.0.unix_timestamp()
     }
+
+    /// Get the signed Unix timestamp (nanoseconds)
+    pub fn sgnunixnsec(&self) -> i128 {
+        self.0.unix_timestamp_nanos()
+    }
 }
 
 impl From<SystemTime> for DateTime {
@@ -114,3 +136,70 @@ impl From<SystemTime> for DateTime {
         Self::from_system_time(&st)
     }
 }
+
+/// A short tag for a version of a file, made from its path (with or
+/// without the leading `/`), size, and last modified time (UNIX
+/// seconds), for content-addressed URLs (such as the thumbnails'
+/// `?v=`). A change in any of them changes the tag.
+pub fn file_version(
+    path: &str,
+    size: Option<u64>,
+    mtime: Option<i64>,
+) -> String {
+    let path = path.trim_start_matches('/');
+    let hash = blake3::hash(format!("{path}\0{size:?}\0{mtime:?}").as_bytes());
+    hash.to_hex()[..16].to_string()
+}
+
+/// Characters to percent-encode in a segment of a URL path, so that
+/// any file name survives the trip (e.g., `#` and `?` aren't taken for
+/// the fragment and the query, and `:` isn't taken for a scheme).
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b':')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Percent-encode each segment of a `/`-separated path
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|seg| utf8_percent_encode(seg, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Format the number of bytes into a human readable string, in SI
+/// units (powers of 1000: kB, MB, ...) or binary ones (powers of
+/// 1024: KB, MB, ...).
+pub fn format_size_bytes(n: u64, si_units: bool) -> String {
+    let (base, units) = if si_units {
+        (
+            1000.0,
+            ["B", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"],
+        )
+    } else {
+        (
+            1024.0,
+            ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"],
+        )
+    };
+    let mut n = n as f64;
+    let mut i = 0;
+    while n >= base && i < units.len() - 1 {
+        n /= base;
+        i += 1;
+    }
+    format!("{:.2} {}", n, units[i])
+}
@@ -0,0 +1,229 @@
+//! Amazon S3 (or compatible) storage backend
+//!
+//! Only built with the `s3` feature.
+//!
+//! S3 has no directories, only keys, so they are emulated by treating
+//! `/` as the separator: a "directory" exists wherever some key has
+//! its path as a prefix. S3 has no links either, so canonicalization
+//! merely joins the paths.
+//!
+//! The chroot names a prefix inside the bucket. For example, with the
+//! chroot `/photos`, the virtual path `/2023/cat.jpg` refers to the
+//! key `photos/2023/cat.jpg`.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use aws_sdk_s3::Client;
+
+use crate::{fs::*, prim::*};
+
+/// A bucket, as an [`OpenFile`] backend
+#[derive(Debug, Clone)]
+pub struct S3File {
+    /// The S3 client
+    client: Client,
+    /// Name of the bucket
+    bucket: String,
+}
+
+impl S3File {
+    /// Serve from the given bucket
+    pub fn new(client: Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+        }
+    }
+
+    /// Serve from the given bucket, configuring the client from the
+    /// environment (`AWS_REGION`, `AWS_ACCESS_KEY_ID`, etc.)
+    pub async fn from_env(bucket: impl Into<String>) -> Self {
+        let config =
+            aws_config::load_defaults(aws_config::BehaviorVersion::latest())
+                .await;
+        Self::new(Client::new(&config), bucket)
+    }
+}
+
+/// Turn a chroot and a virtual path into an S3 key, without any
+/// leading or trailing `/`.
+fn key(chroot: &RealPath, virt_path: &VirtualPath) -> Result<String> {
+    let path = chroot.join(virt_path);
+    let parts = path
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(c) => Some(c.to_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_err("bad utf-8")?;
+    Ok(parts.join("/"))
+}
+
+/// Convert an S3 date to a [`DateTime`]
+fn s3_date(dt: &aws_sdk_s3::primitives::DateTime) -> Option<DateTime> {
+    let secs = u64::try_from(dt.secs()).ok()?;
+    let st = SystemTime::UNIX_EPOCH
+        + Duration::from_secs(secs)
+        + Duration::from_nanos(dt.subsec_nanos().into());
+    Some(st.into())
+}
+
+/// Make the metadata of an emulated directory
+fn directory(name: String) -> FileMetadata {
+    FileMetadata {
+        file_type: FileType::Directory,
+        file_name: name,
+        size: None,
+        last_modified: None,
+        mode: None,
+        created: None,
+        accessed: None,
+        raw_name: None,
+    }
+}
+
+#[async_trait]
+impl OpenFile for S3File {
+    #[instrument(err)]
+    async fn open_file(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<Box<dyn TokioFile>> {
+        let obj = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key(chroot, virt_path)?)
+            .send()
+            .await
+            .context("get object")?;
+        Ok(Box::new(Box::pin(obj.body.into_async_read())))
+    }
+
+    #[instrument(err)]
+    async fn read_metadata(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadata> {
+        let key = key(chroot, virt_path)?;
+        let name = virt_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        // The bucket root is always a directory
+        if key.is_empty() {
+            return Ok(directory(name));
+        }
+
+        // An object?
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await;
+        if let Ok(head) = head {
+            return Ok(FileMetadata {
+                file_type: FileType::RegularFile,
+                file_name: name,
+                size: head.content_length().and_then(|n| n.try_into().ok()),
+                last_modified: head.last_modified().and_then(s3_date),
+                mode: None,
+                created: None,
+                accessed: None,
+                raw_name: None,
+            });
+        }
+
+        // If not, a prefix of some object?
+        let list = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(format!("{key}/"))
+            .max_keys(1)
+            .send()
+            .await
+            .context("list objects")?;
+        if list.key_count().unwrap_or_default() > 0 {
+            return Ok(directory(name));
+        }
+        Err(anyhow!("no such object or prefix: {key:?}"))
+    }
+
+    async fn canonicalize(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<PathBuf> {
+        Ok(chroot.join(virt_path))
+    }
+
+    #[instrument(err)]
+    async fn list_directory(
+        &self,
+        chroot: &RealPath,
+        virt_path: &VirtualPath,
+    ) -> Result<FileMetadataStream> {
+        let key = key(chroot, virt_path)?;
+        let prefix = if key.is_empty() {
+            key
+        } else {
+            format!("{key}/")
+        };
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+        let stream = try_stream! {
+            while let Some(page) = pages.next().await {
+                let page = page.context("list objects")?;
+                for cp in page.common_prefixes() {
+                    let Some(name) = cp
+                        .prefix()
+                        .and_then(|p| p.strip_prefix(&prefix))
+                        .map(|p| p.trim_end_matches('/'))
+                    else {
+                        continue;
+                    };
+                    yield directory(name.to_string());
+                }
+                for obj in page.contents() {
+                    let Some(name) = obj
+                        .key()
+                        .and_then(|k| k.strip_prefix(&prefix))
+                        .filter(|k| !k.is_empty())
+                    else {
+                        continue;
+                    };
+                    yield FileMetadata {
+                        file_type: FileType::RegularFile,
+                        file_name: name.to_string(),
+                        size: obj.size().and_then(|n| n.try_into().ok()),
+                        last_modified: obj.last_modified().and_then(s3_date),
+                        mode: None,
+                        created: None,
+                        accessed: None,
+                        raw_name: None,
+                    };
+                }
+            }
+        };
+        Ok(Box::pin(stream))
+    }
+}
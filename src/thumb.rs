@@ -1,19 +1,532 @@
 //! Thumbnailing
 
+use std::{panic::AssertUnwindSafe, str::FromStr, sync::Arc};
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        webp::WebPDecoder,
+    },
+    imageops::FilterType,
+    AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat,
+};
+
 use crate::prim::*;
 
+/// A pool of threads of its own for CPU-heavy work (decoding,
+/// resampling, encoding), run by [`rayon`]
+///
+/// At most as many jobs run at once as there are threads, however
+/// many requests want them; the rest wait their turn (idle threads
+/// steal work from busy ones). Since the threads are not Tokio's, the
+/// work neither holds up the threads that handle requests nor
+/// competes with blocking I/O (such as on slow network mounts) for
+/// the blocking threads. Clones share the pool, which winds down once
+/// the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct CpuPool(Arc<rayon::ThreadPool>);
+
+impl CpuPool {
+    /// Start a pool of `size` threads
+    pub fn new(size: usize) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(size.max(1))
+            .thread_name(|i| format!("gagaga-cpu-{i}"))
+            .build()
+            .expect("expect to start the CPU pool");
+        Self(Arc::new(pool))
+    }
+
+    /// Run a job on the pool, and wait for its result
+    ///
+    /// A job that panics gives an error (with the panic's message),
+    /// and leaves the pool as it was. If the wait is given up (such as
+    /// when the request times out, or the client leaves) before the
+    /// job's turn comes, the job is skipped; once it runs, though, it
+    /// runs to the end.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.0.spawn(move || {
+            if tx.is_closed() {
+                return;
+            }
+            // (Rayon would abort the process on a panic.)
+            _ = tx.send(std::panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        match rx.await.context("CPU pool is gone")? {
+            Ok(t) => Ok(t),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(|s| &s[..]))
+                    .unwrap_or("(no message)");
+                Err(anyhow!("CPU job panicked: {message}"))
+            }
+        }
+    }
+}
+
+impl Default for CpuPool {
+    /// One thread per physical core
+    fn default() -> Self {
+        Self::new(num_cpus::get_physical())
+    }
+}
+
+/// How to make an image fit the thumbnail's box
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThumbMode {
+    /// Shrink the image to fit inside the box, keeping the aspect
+    /// ratio (so one side may come out shorter)
+    #[default]
+    Fit,
+    /// Shrink the image to cover the box, keeping the aspect ratio,
+    /// and then crop it around the center to fill the box exactly
+    Cover,
+}
+
+impl FromStr for ThumbMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fit" => Ok(Self::Fit),
+            "cover" => Ok(Self::Cover),
+            _ => Err(anyhow!("unknown thumbnail mode: {s:?}")),
+        }
+    }
+}
+
+/// Resampling filter to downscale with, in place of the default fast
+/// one. Listed roughly from fastest (and blockiest) to slowest (and
+/// sharpest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resample {
+    /// Nearest neighbor
+    Nearest,
+    /// Linear (tent)
+    Triangle,
+    /// Cubic (Catmull-Rom)
+    CatmullRom,
+    /// Lanczos with a window of 3
+    Lanczos3,
+}
+
+impl FromStr for Resample {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Self::Nearest),
+            "triangle" => Ok(Self::Triangle),
+            "catmull-rom" => Ok(Self::CatmullRom),
+            "lanczos3" => Ok(Self::Lanczos3),
+            _ => Err(anyhow!("unknown resampling filter: {s:?}")),
+        }
+    }
+}
+
+impl From<Resample> for FilterType {
+    fn from(r: Resample) -> Self {
+        match r {
+            Resample::Nearest => Self::Nearest,
+            Resample::Triangle => Self::Triangle,
+            Resample::CatmullRom => Self::CatmullRom,
+            Resample::Lanczos3 => Self::Lanczos3,
+        }
+    }
+}
+
+/// An unsharp mask, applied after downscaling to undo some of the
+/// softness that comes with it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sharpen {
+    /// Standard deviation of the Gaussian blur the mask is made with
+    pub sigma: f32,
+    /// Leave alone pixels that differ from the blurred ones by less
+    /// than this (so that flat areas don't get noisy)
+    pub threshold: i32,
+}
+
+impl Default for Sharpen {
+    fn default() -> Self {
+        // Mild, since thumbnails are small.
+        Self {
+            sigma: 0.5,
+            threshold: 2,
+        }
+    }
+}
+
+/// Shrink an image into (W x H) as the mode says (see [`ThumbMode`]),
+/// with the filter, if given, or else a fast one
+fn fit<const W: u32, const H: u32>(
+    img: DynamicImage,
+    mode: ThumbMode,
+    filter: Option<Resample>,
+) -> DynamicImage {
+    match mode {
+        ThumbMode::Fit => match filter {
+            Some(filter) => img.resize(W, H, filter.into()),
+            None => img.thumbnail(W, H),
+        },
+        ThumbMode::Cover => {
+            // Scale so that the shorter side (relative to the box)
+            // fits, and the longer one overflows. Then, crop.
+            let (w, h) = (img.width().max(1), img.height().max(1));
+            let scale = f64::max(W as f64 / w as f64, H as f64 / h as f64);
+            let sw = ((w as f64 * scale).ceil() as u32).max(W);
+            let sh = ((h as f64 * scale).ceil() as u32).max(H);
+            let img = match filter {
+                Some(filter) => img.resize_exact(sw, sh, filter.into()),
+                None => img.thumbnail_exact(sw, sh),
+            };
+            let (x, y) = ((sw - W) / 2, (sh - H) / 2);
+            img.crop_imm(x, y, W, H)
+        }
+    }
+}
+
+/// Limits on decoding an image, against files that are small but
+/// expand into huge pictures (decompression bombs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Refuse images of more pixels than this (as the header says),
+    /// before decoding them at all
+    pub max_pixels: u64,
+    /// Give up on decoding that would allocate more than this (bytes)
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    /// 50 megapixels, and 256 MiB
+    fn default() -> Self {
+        Self {
+            max_pixels: 50_000_000,
+            max_alloc: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Refuse an image of these dimensions, if over the pixel budget
+    ///
+    /// The dimensions come from the header, which costs nothing to
+    /// forge, so a refusal is logged as a warning (a small file that
+    /// declares a huge picture is likely a decompression bomb).
+    fn check(&self, (w, h): (u32, u32)) -> Result<()> {
+        let pixels = w as u64 * h as u64;
+        if pixels > self.max_pixels {
+            tracing::warn!(
+                "refuse to decode {w}x{h} image (> {} pixels)",
+                self.max_pixels
+            );
+            return Err(anyhow!(
+                "image too large: {w}x{h} > {} pixels",
+                self.max_pixels
+            ));
+        }
+        Ok(())
+    }
+
+    /// The limits in the form of the decoders
+    fn to_image(self) -> image::io::Limits {
+        let mut limits = image::io::Limits::default();
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+/// Decode an image file within the limits
+fn decode(file: &[u8], limits: DecodeLimits) -> Result<DynamicImage> {
+    let reader = || {
+        image::io::Reader::new(std::io::Cursor::new(file))
+            .with_guessed_format()
+            .context("while guessing image format")
+    };
+    let dimensions = reader()?
+        .into_dimensions()
+        .context("while reading image dimensions")?;
+    limits.check(dimensions)?;
+    let mut reader = reader()?;
+    reader.limits(limits.to_image());
+    reader.decode().context("while loading image from buffer")
+}
+
 /// Thumbnail an image file into JPEG with a maximum width and height
 /// (while keeping the aspect ratio) and a quality (0-100).
-#[instrument]
+///
+/// If `filter` is given, the image is resampled with it (instead of
+/// the default fast filter). If `sharpen` is given, the downscaled
+/// image is sharpened with it. Images that declare (in the header)
+/// more pixels than the `limits` allow are refused before decoding.
+#[instrument(skip(file))]
 pub fn ithumbjpg<const W: u32, const H: u32, const Q: u8>(
     file: &[u8],
+    mode: ThumbMode,
+    filter: Option<Resample>,
+    sharpen: Option<Sharpen>,
+    limits: DecodeLimits,
 ) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(file)
-        .context("while loading image from buffer")?;
-    let img = img.thumbnail(W, H);
+    let img = decode(file, limits)?;
+    let img = fit::<W, H>(img, mode, filter);
+    let img = match sharpen {
+        Some(Sharpen { sigma, threshold }) => img.unsharpen(sigma, threshold),
+        None => img,
+    };
     let fmt = image::ImageOutputFormat::Jpeg(Q);
     let mut cur = std::io::Cursor::new(vec![]);
     img.write_to(&mut cur, fmt)
         .context("while writing image data to in-memory buffer")?;
     Ok(cur.into_inner())
 }
+
+/// Limits on animated thumbnails (see [`ithumbanim`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimLimits {
+    /// Give up on images with more frames than this
+    pub max_frames: usize,
+    /// Give up on thumbnails larger than this (bytes)
+    pub max_bytes: usize,
+}
+
+impl Default for AnimLimits {
+    fn default() -> Self {
+        Self {
+            max_frames: 50,
+            max_bytes: 512 * 1024,
+        }
+    }
+}
+
+/// Thumbnail an animated image file (GIF or WebP) into an animated
+/// GIF, shrinking each frame as [`ithumbjpg`] does (but without
+/// sharpening), and keeping the timing.
+///
+/// Gives `None` if the image isn't animated (or has just one frame),
+/// or if it's over the limits, so that a still thumbnail can be made
+/// instead. Images over the `decode` limits (per frame) are refused.
+///
+/// Each frame is shrunk as soon as it's decoded, so only one is ever
+/// held at full size. Since each still costs a full decode, the frames
+/// together may decode to no more than `decode.max_alloc` bytes either
+/// (or else `None`).
+#[instrument(skip(file))]
+pub fn ithumbanim<const W: u32, const H: u32>(
+    file: &[u8],
+    mode: ThumbMode,
+    filter: Option<Resample>,
+    limits: AnimLimits,
+    decode: DecodeLimits,
+) -> Result<Option<Vec<u8>>> {
+    let cur = std::io::Cursor::new(file);
+    let frames = match image::guess_format(file) {
+        Ok(ImageFormat::Gif) => {
+            let mut decoder =
+                GifDecoder::new(cur).context("while reading GIF")?;
+            decode.check(decoder.dimensions())?;
+            decoder
+                .set_limits(decode.to_image())
+                .context("while limiting GIF")?;
+            decoder.into_frames()
+        }
+        Ok(ImageFormat::WebP) => {
+            let mut decoder =
+                WebPDecoder::new(cur).context("while reading WebP")?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decode.check(decoder.dimensions())?;
+            decoder
+                .set_limits(decode.to_image())
+                .context("while limiting WebP")?;
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    // Decode and shrink, one frame at a time. Decode at most one
+    // frame too many, to tell if there are.
+    let mut shrunk = vec![];
+    let mut decoded = 0u64;
+    for frame in frames.take(limits.max_frames + 1) {
+        let frame = frame.context("while decoding frame")?;
+        let (w, h) = frame.buffer().dimensions();
+        decoded += w as u64 * h as u64 * 4;
+        if decoded > decode.max_alloc {
+            return Ok(None);
+        }
+        let delay = frame.delay();
+        let img = DynamicImage::ImageRgba8(frame.into_buffer());
+        let img = fit::<W, H>(img, mode, filter).to_rgba8();
+        shrunk.push(Frame::from_parts(img, 0, 0, delay));
+    }
+    if shrunk.len() < 2 || shrunk.len() > limits.max_frames {
+        return Ok(None);
+    }
+
+    // Encode, looping forever
+    let mut buf = vec![];
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buf, 10);
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .context("while setting GIF to loop")?;
+        encoder
+            .encode_frames(shrunk)
+            .context("while writing GIF frames")?;
+    }
+    Ok((buf.len() <= limits.max_bytes).then_some(buf))
+}
+
+/// Find the average color of an image file, as `#rrggbb`
+///
+/// The image is shrunk quickly (as for a thumbnail) and then averaged
+/// down to a single pixel, which is cheap next to decoding it.
+#[instrument(skip(file))]
+pub fn iavgcolor(file: &[u8], limits: DecodeLimits) -> Result<String> {
+    let img = decode(file, limits)?
+        .thumbnail(64, 64)
+        .resize_exact(1, 1, FilterType::Triangle)
+        .to_rgb8();
+    let [r, g, b] = img.get_pixel(0, 0).0;
+    Ok(format!("#{r:02x}{g:02x}{b:02x}"))
+}
+
+/// Read the width and height of an image file from its header,
+/// without decoding the pixels. The file may be cut short, as long as
+/// the header is all there.
+#[instrument(skip(file))]
+pub fn idimensions(file: &[u8]) -> Result<(u32, u32)> {
+    image::io::Reader::new(std::io::Cursor::new(file))
+        .with_guessed_format()
+        .context("while guessing image format")?
+        .into_dimensions()
+        .context("while reading image dimensions")
+}
+
+/// Make a BlurHash (with 4 by 3 components) of an image file, from a
+/// version of it small enough for this to be cheap
+#[instrument(skip(file))]
+pub fn iblurhash(file: &[u8], limits: DecodeLimits) -> Result<String> {
+    let img = decode(file, limits)?.thumbnail(32, 32).to_rgba8();
+    let (w, h) = img.dimensions();
+    blurhash::encode(4, 3, w, h, img.as_raw())
+        .context("while encoding blurhash")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Delay, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// An animated GIF of `n` frames of 64 by 64
+    fn gif(n: u8) -> Vec<u8> {
+        let mut buf = vec![];
+        let mut encoder = GifEncoder::new(&mut buf);
+        for i in 0..n {
+            let img = RgbaImage::from_pixel(64, 64, Rgba([i * 50, 0, 0, 255]));
+            let delay = Delay::from_numer_denom_ms(100, 1);
+            encoder
+                .encode_frame(Frame::from_parts(img, 0, 0, delay))
+                .unwrap();
+        }
+        drop(encoder);
+        buf
+    }
+
+    #[tokio::test]
+    async fn pool_runs_jobs_at_most_size_at_once() {
+        let pool = CpuPool::new(2);
+        let running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let jobs = (0..8).map(|_| {
+            let running = running.clone();
+            pool.run(move || {
+                use std::sync::atomic::Ordering::SeqCst;
+                let now = running.fetch_add(1, SeqCst) + 1;
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                running.fetch_sub(1, SeqCst);
+                now
+            })
+        });
+        let most = futures_util::future::join_all(jobs)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .max();
+        assert!(most.is_some_and(|most| most <= 2), "{most:?}");
+    }
+
+    #[tokio::test]
+    async fn pool_turns_panics_into_errors() {
+        let pool = CpuPool::new(1);
+        let e = pool.run(|| -> u8 { panic!("boom") }).await.unwrap_err();
+        assert!(format!("{e}").contains("boom"), "{e}");
+
+        // The pool still works
+        assert_eq!(pool.run(|| 1 + 1).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn pool_skips_jobs_given_up_on() {
+        use std::{
+            sync::atomic::{AtomicBool, Ordering::SeqCst},
+            time::Duration,
+        };
+
+        // Keep the only thread busy for a while
+        let pool = CpuPool::new(1);
+        let mut busy = Box::pin(
+            pool.run(|| std::thread::sleep(Duration::from_millis(100))),
+        );
+        assert!(futures_util::poll!(&mut busy).is_pending());
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let job = {
+            let ran = ran.clone();
+            pool.run(move || ran.store(true, SeqCst))
+        };
+        let waited = tokio::time::timeout(Duration::from_millis(10), job).await;
+        assert!(waited.is_err());
+
+        busy.await.unwrap();
+        pool.run(|| ()).await.unwrap();
+        assert!(!ran.load(SeqCst));
+    }
+
+    #[test]
+    fn animation_within_the_frame_budget() {
+        let anim = ithumbanim::<16, 16>(
+            &gif(3),
+            ThumbMode::Fit,
+            None,
+            AnimLimits::default(),
+            DecodeLimits::default(),
+        )
+        .unwrap();
+        assert!(anim.is_some());
+    }
+
+    #[test]
+    fn animation_over_the_frame_budget_is_still() {
+        // Room for two of the three frames at full size
+        let decode = DecodeLimits {
+            max_alloc: 2 * 64 * 64 * 4,
+            ..Default::default()
+        };
+        let anim = ithumbanim::<16, 16>(
+            &gif(3),
+            ThumbMode::Fit,
+            None,
+            AnimLimits::default(),
+            decode,
+        )
+        .unwrap();
+        assert!(anim.is_none());
+    }
+}